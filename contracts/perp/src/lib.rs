@@ -1,6 +1,7 @@
 #![no_std]
 use soroban_sdk::{
-    contract, contractclient, contracterror, contractimpl, contracttype, symbol_short, vec, Address, Env, String, Symbol
+    contract, contractclient, contracterror, contractimpl, contracttype, panic_with_error, symbol_short, vec, Address, BytesN, Env,
+    Map, String, Symbol, TryFromVal, Val, Vec
 };
 
 // Oracle integration
@@ -14,9 +15,35 @@ const DEC_P: i128 = 1_000_000;                    // price 1e6
 const DEC_F: i128 = 1_000_000_000_000_000_000;    // funding 1e18
 const IMR_BP: i128 = 2_000;                       // 20% init margin
 const MMR_BP: i128 = 1_000;                       // 10% maint margin
-const BONUS_BP: i128 = 200;                       // 2% liquidation bonus
+const BONUS_BP: i128 = 200;                       // 2% max liquidation bonus
+const MIN_BONUS_BP: i128 = 25;                    // 0.25% bonus when the auction starts
+const BONUS_RAMP_SECS: u64 = 600;                 // min → max bonus over 10 minutes
 const MAX_DRIFT_BP: i128 = 100;                 // ±1% max premium/discount
 const FEE_BP: i128 = 5;                         // 0.05% swap fee (placeholder)
+const MAX_FEE_BP: i128 = 100;                   // upper bound for a timelocked fee change
+const PROTOCOL_FEE_BP: i128 = 0;                // share of each trading fee kept by the treasury
+const FUNDING_PERIOD: u64 = 1800;                 // 30 minutes between funding updates
+const MAX_FUNDING_VEL_BP: i128 = 1_000;           // 10% per day
+const MAX_UTILIZATION_BP: i128 = 8_000;           // net skew notional ≤ 80% of vault NAV
+const LP_DEPOSIT_COOLDOWN: u64 = 86_400;          // deposit → earliest withdrawal request
+const LP_WITHDRAW_COOLDOWN: u64 = 86_400;         // withdrawal request → execution
+const SETTLEMENT_BAND_BP: i128 = 200;             // settlement price within ±2% of oracle
+const DEFAULT_TIMELOCK_DELAY: u64 = 86_400;       // queue → earliest execution of a parameter change
+const MAX_TIMELOCK_DELAY: u64 = 2_592_000;        // 30 days
+const SCHEMA_VERSION: u32 = 2;                    // storage layout written by this code
+const EVENT_VERSION: u32 = 1;                     // layout of the event structs below
+
+// Storage rent, in ledgers (~5s each). An entry is extended to AMOUNT once
+// its remaining TTL drops below THRESHOLD.
+const DAY_IN_LEDGERS: u32 = 17_280;
+const INSTANCE_BUMP_THRESHOLD: u32 = 7 * DAY_IN_LEDGERS;  // config, totals, status flags
+const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const ACCOUNT_BUMP_THRESHOLD: u32 = 7 * DAY_IN_LEDGERS;   // per-trader and per-LP entries
+const ACCOUNT_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+const MARKET_BUMP_THRESHOLD: u32 = 15 * DAY_IN_LEDGERS;   // per-market state and parameters
+const MARKET_BUMP_AMOUNT: u32 = 60 * DAY_IN_LEDGERS;
+const PROTOCOL_BUMP_THRESHOLD: u32 = 30 * DAY_IN_LEDGERS; // vault, roles, timelock queue
+const PROTOCOL_BUMP_AMOUNT: u32 = 90 * DAY_IN_LEDGERS;
 
 // Market-specific parameters --------------------------------------------------
 // IMPORTANT: Markets are now identified by their base symbol (e.g. "BTC", "XLM" …).
@@ -26,7 +53,7 @@ const FEE_BP: i128 = 5;                         // 0.05% swap fee (placeholder)
 // position.size (6-dec fixed-point for XLM, micro-BTC = 1 e-6 BTC, micro-ETH etc.).
 
 fn fetch_oracle_price(env: &Env, sym: Symbol) -> Result<i128, Error> {
-    let oracle_address = Address::from_string(&String::from_str(env, ORACLE_ID));
+    let oracle_address = oracle_address(env);
     let oracle = Oracle::new(env, &oracle_address);
     let pd = oracle.lastprice(&Asset::Other(sym.clone()))
         .ok_or(Error::OracleUnavailable)?;
@@ -37,6 +64,12 @@ fn fetch_oracle_price(env: &Env, sym: Symbol) -> Result<i128, Error> {
     Ok(pd.price / ORACLE_DIVISOR)
 }
 
+fn oracle_address(env: &Env) -> Address {
+    env.storage().instance()
+        .get(&DataKey::Oracle)
+        .unwrap_or_else(|| Address::from_string(&String::from_str(env, ORACLE_ID)))
+}
+
 fn default_skew_scale(sym: &Symbol) -> Result<i128, Error> {
     if *sym == symbol_short!("XLM") {
         Ok(10_000_000_000)      // 10 M XLM (size is 1e6 precision → 10 000 000 XLM)
@@ -69,6 +102,25 @@ pub enum Error {
     SelfLiquidation = 14,
     SlippageExceeded = 15,
     Overflow = 16,
+    PositionsOpen = 17,
+    NotCrossMargin = 18,
+    UtilizationExceeded = 19,
+    CooldownActive = 20,
+    OpenInterestCap = 21,
+    ReduceOnly = 22,
+    MarketSettled = 23,
+    MarketNotSettled = 24,
+    PriceOutOfBounds = 25,
+    ShutDown = 26,
+    NotShutDown = 27,
+    TallyPending = 28,
+    InvalidRole = 29,
+    NoPendingAdmin = 30,
+    TimelockActive = 31,
+    ChangeNotFound = 32,
+    MigrationPending = 33,
+    AssetNotListed = 34,
+    DepositCapExceeded = 35,
 }
 
 #[contracttype]
@@ -81,7 +133,7 @@ pub struct Reserve {
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FundingData {
-    pub rate: i128,
+    pub index: i128,              // cumulative funding per unit of size, 1e18
     pub last_update: u64,
 }
 
@@ -92,19 +144,739 @@ pub struct Position {
     pub notional: i128,
     pub margin: i128,
     pub funding_index: i128,
+    pub last_update: u64,         // timestamp of the last trade or margin change
+}
+
+// Schema v1 layouts, still decoded so entries written before `migrate` keep working
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FundingDataV1 {
+    pub rate: i128,
+    pub last_update: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionV1 {
+    pub size: i128,
+    pub notional: i128,
+    pub margin: i128,
+    pub funding_index: i128,
+}
+
+// Gross open interest per market, in base units
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenInterest {
+    pub long: i128,
+    pub short: i128,
+}
+
+// Everything a trade or funding update reads and writes for one market, kept
+// in a single entry so each invocation touches one ledger key per market
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarketState {
+    pub reserve: Reserve,
+    pub funding: FundingData,
+    pub net_oi: i128,              // net open interest (longs – shorts)
+    pub skew_scale: i128,          // scale used to normalise skew
+    pub net_notional: i128,        // Σ signed entry notional (longs +, shorts −)
+    pub open_interest: OpenInterest,
+}
+
+// Per-trader aggregate, maintained on every collateral and position write so
+// risk checks only visit the markets the trader is actually in
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Account {
+    pub collateral: i128,
+    pub margin_used: i128,         // Σ margin of open positions
+    pub markets: Vec<Symbol>,      // markets with an open position
+}
+
+// Cumulative realized figures of one trader in one market, in collateral units
+#[contracttype]
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TradeLedger {
+    pub realized_pnl: i128,       // closes, liquidations, settlement and shutdown claims
+    pub fees_paid: i128,
+    pub funding_paid: i128,
+    pub funding_received: i128,
+    pub liquidation_losses: i128, // bonuses paid and margin handed to the backstop
+    pub volume: i128,             // Σ |size| × fill price, every side of every trade
+}
+
+// Per-market size caps, in base units (i128::MAX = uncapped)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarketLimits {
+    pub max_long_oi: i128,
+    pub max_short_oi: i128,
+    pub max_position_size: i128, // per account, either side
+}
+
+// One step of a market's margin schedule: positions whose current notional is
+// at least `min_notional` (quote units) use these rates. Tiers are sorted by
+// `min_notional`, the first starts at 0, and rates never decrease with size.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarginTier {
+    pub min_notional: i128,
+    pub imr_bp: i128,
+    pub mmr_bp: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarketStats {
+    pub long_oi: i128,
+    pub short_oi: i128,
+    pub net_oi: i128,
+    pub long_oi_value: i128,      // long OI × mark, quote units
+    pub short_oi_value: i128,     // short OI × mark, quote units
+    pub skew_scale: i128,
+    pub skew_bp: i128,            // mark premium (+) / discount (−) over oracle
+    pub oracle_price: i128,
+    pub mark_price: i128,
+    pub funding_index: i128,      // cumulative funding, as stored in `FundingData.index`
+    pub funding_velocity: i128,   // index change per day at the current skew
+    pub funding_last_update: u64,
+    pub limits: MarketLimits,
+    pub status: MarketStatus,     // effective status, global status included
+}
+
+// Liquidity vault acting as counterparty to all traders. `balance` is cash
+// owned by LPs; NAV additionally marks the traders' aggregate unrealised PnL
+// against it.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Vault {
+    pub balance: i128,
+    pub total_shares: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VaultConfig {
+    pub max_utilization_bp: i128, // max net skew notional as a share of NAV
+    pub deposit_cooldown: u64,    // seconds after a deposit before a withdrawal can be requested
+    pub withdraw_cooldown: u64,   // seconds between a withdrawal request and its execution
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LpPosition {
+    pub shares: i128,
+    pub last_deposit: u64,
+    pub pending_shares: i128,     // shares queued by `lp_request_withdraw`
+    pub requested_at: u64,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VaultView {
+    pub balance: i128,
+    pub nav: i128,
+    pub total_shares: i128,
+    pub share_price: i128,        // NAV per share, 1e6 precision
+    pub exposure: i128,           // Σ |net OI| × mark across markets
+    pub max_exposure: i128,       // exposure allowed by the utilization cap
+}
+
+// Isolated: each position's losses are capped at its own margin and it is
+// liquidated on its own ratio. Cross: positions share the account's equity and
+// liquidation triggers when equity falls below total maintenance margin.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum MarginMode {
+    Isolated = 0,
+    Cross = 1,
+}
+
+// Trading state of the whole protocol or of one market. A market runs at the
+// stricter of its own status and the global one. ReduceOnly blocks opens and
+// margin withdrawals but lets positions close, be liquidated and pay funding;
+// Halted blocks everything.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum MarketStatus {
+    Active = 0,
+    ReduceOnly = 1,
+    Halted = 2,
+}
+
+// Final price of a delisted market. Open positions stay in storage until
+// their owners call `claim_settlement`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarketSettlement {
+    pub price: i128,
+    pub settled_at: u64,
+}
+
+// Owner: the `Admin` address, holds every role and manages the others.
+// Guardian: can halt or restrict markets and operations, never relax them.
+// RiskManager: margin, liquidation, vault and market parameters.
+// OracleManager: the price oracle. Treasurer: withdraws the protocol treasury.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Role {
+    Owner = 0,
+    Guardian = 1,
+    RiskManager = 2,
+    OracleManager = 3,
+    Treasurer = 4,
+}
+
+// Non-USD collateral token. Balances are valued at the oracle price of
+// `asset` and count towards margin net of `haircut_bp`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralAsset {
+    pub asset: Symbol,            // oracle asset the token is priced as
+    pub decimals: u32,            // token decimals, to convert balances to collateral units
+    pub haircut_bp: i128,         // share of the value that does not count (10 000 = none counts)
+    pub deposit_cap: i128,        // Σ balances across accounts, in token units (0 = closed)
+}
+
+// Parameter update that only takes effect through the timelock
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParamChange {
+    MarginTiers(Symbol, Vec<MarginTier>),
+    MarketLimits(Symbol, MarketLimits),
+    LiquidationAuction(LiquidationAuction),
+    VaultConfig(VaultConfig),
+    FeeBp(i128),
+    ProtocolFeeBp(i128),                       // bp of each trading fee credited to the treasury
+    Oracle(Address),
+    CollateralToken(Address),
+    CollateralAsset(Address, CollateralAsset), // lists or updates a non-USD collateral token
+    TimelockDelay(u64),
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedChange {
+    pub id: u64,
+    pub change: ParamChange,
+    pub proposer: Address,
+    pub eta: u64,                 // earliest timestamp `execute_change` accepts
+}
+
+// Frozen state after `shutdown`. Accounts are tallied at `prices`; once all
+// are, claims open and are scaled by `assets / total_claims` when the
+// contract holds less than it owes.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShutdownState {
+    pub shutdown_at: u64,
+    pub prices: Map<Symbol, i128>, // mark (or settlement) price per market
+    pub collateral_prices: Map<Address, i128>, // oracle price per listed collateral token
+    pub book: i128,                // Σ trader collateral + vault balance
+    pub assets: i128,              // collateral token held by the contract, less the treasury's
+    pub trader_claims: i128,       // Σ final equity of the accounts tallied so far
+    pub tallied: bool,             // every account is tallied and claims are open
+    pub vault_nav: i128,           // book − trader claims, shared by LPs once tallied
+    pub total_claims: i128,        // trader claims + vault NAV once tallied
+    pub total_shares: i128,
+    pub paid: i128,                // paid out so far, never above `assets`
+}
+
+// Entrypoint families that can be paused individually, whatever the status
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Operation {
+    Deposit = 0,       // deposit_collateral, deposit_token_collateral
+    Withdraw = 1,      // withdraw_collateral, withdraw_token_collateral
+    Open = 2,          // open_position
+    Close = 3,         // close_position, claim_settlement
+    Liquidate = 4,     // liquidate, flag_liquidation, liquidate_batch, liquidate_account, liquidate_collateral
+    AddMargin = 5,     // add_margin
+    RemoveMargin = 6,  // remove_margin
+    LpDeposit = 7,     // lp_deposit
+    LpWithdraw = 8,    // lp_request_withdraw, lp_withdraw
+    Funding = 9,       // poke_funding
+    MarginMode = 10,   // set_margin_mode
+}
+
+// Dutch-auction liquidation reward: the bonus rises linearly from
+// `min_bonus_bp` to `max_bonus_bp` over `ramp_seconds` after a position is
+// first flagged as liquidatable.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LiquidationAuction {
+    pub min_bonus_bp: i128,
+    pub max_bonus_bp: i128,
+    pub ramp_seconds: u64,
+}
+
+// Per-entry outcome of `liquidate_batch`
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LiquidationResult {
+    Liquidated(i128), // bonus paid to the liquidator
+    Healthy,
+    NoPosition,
+    Failed(u32),      // `Error` code that stopped this entry
+}
+
+// Read-only snapshot of a single position, valued at the current mark price.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PositionView {
+    pub symbol: Symbol,
+    pub size: i128,
+    pub notional: i128,
+    pub margin: i128,
+    pub entry_price: i128,
+    pub mark_price: i128,
+    pub unrealized_pnl: i128,
+    pub accrued_funding: i128,   // positive = owed by the trader
+    pub margin_ratio: i128,      // bp, same value `liquidate` compares to MMR
+    pub imr_bp: i128,            // margin tier for the current notional
+    pub mmr_bp: i128,
+    pub liquidation_price: i128, // mark at which the position (isolated) or account (cross) hits MMR (0 = none)
+    pub last_update: u64,        // last trade or margin change (0 = not touched since schema v1)
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AccountView {
+    pub margin_mode: MarginMode,
+    pub collateral: i128,         // USD collateral, negative while the account owes a shortfall
+    pub tokens: Map<Address, i128>, // non-USD collateral balances
+    pub token_value: i128,        // their value after haircuts
+    pub margin_used: i128,        // Σ margin reserved by open positions
+    pub free_collateral: i128,
+    pub equity: i128,             // collateral + token value + Σ(uPnL − accrued funding)
+    pub maintenance_margin: i128, // Σ current notional × tiered MMR
+    pub positions: Vec<PositionView>,
+}
+
+// Result of `quote_open` / `quote_close`.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TradeQuote {
+    pub fill_price: i128,
+    pub price_impact_bp: i128,   // mark move caused by the trade
+    pub fee: i128,               // AMM fee retained by the pool
+    pub required_margin: i128,   // initial margin for the traded notional (0 when reducing)
+    pub margin_ratio: i128,      // resulting position ratio at the post-trade mark
+    pub realized_pnl: i128,
+    pub funding_payment: i128,   // positive = paid by the trader
+}
+
+// State changes `open_position` would apply, computed without writing storage
+struct OpenPlan {
+    mark_price: i128,
+    post_mark_price: i128,
+    required_margin: i128,
+    reserve: Reserve,
+    fee: i128,
+    net_oi: i128,
+    net_notional: i128,
+    open_interest: OpenInterest,
+    funding_payment: i128,
+    position: Position,
+}
+
+// State changes `close_position` would apply; `position` is None when fully closed
+struct ClosePlan {
+    mark_price: i128,
+    post_mark_price: i128,
+    reserve: Reserve,
+    fee: i128,
+    net_oi: i128,
+    net_notional: i128,
+    open_interest: OpenInterest,
+    pnl: i128,
+    realized_pnl: i128,           // after the isolated margin cap
+    funding_payment: i128,
+    settlement: i128,             // change to the trader's collateral
+    position: Option<Position>,
+}
+
+// State changes a liquidation would apply; `takeover` is set when the
+// backstop inherits the position instead of the AMM closing it
+struct LiquidationPlan {
+    current_notional: i128,
+    bonus: i128,
+    pnl: i128,                    // at the mark price
+    realized_pnl: i128,           // after the isolated margin cap
+    funding_paid: i128,
+    trader_delta: i128,           // change to the trader's collateral
+    remaining_margin: i128,
+    market: MarketState,
+    takeover: Option<Takeover>,
+}
+
+struct Takeover {
+    backstop: Address,
+    position: Position,           // the backstop's position after inheriting
+    notional: i128,
+    funding_payment: i128,        // settled on the backstop's existing position
 }
 
 #[contracttype]
 pub enum DataKey {
     Admin,
-    Paused,
-    Reserves(Symbol),
-    Funding(Symbol),
-    Collateral(Address),
+    Paused,            // schema 1 global pause flag, mapped to GlobalStatus by `migrate`
+    GlobalStatus,
+    MarketStatus(Symbol),
+    OperationPaused(Operation),
+    Settlement(Symbol),
+    TotalCollateral,   // Σ trader collateral, for solvency at shutdown
+    RoleMembers(Role),
+    Oracle,            // oracle contract, ORACLE_ID until set
+    PendingAdmin,      // proposed owner awaiting `accept_admin`
+    FeeBp,
+    ProtocolFeeBp,
+    Treasury,          // Map<Address, i128> tokens owned by the protocol
+    TimelockDelay,
+    NextChangeId,
+    QueuedChanges,     // Vec<QueuedChange> awaiting execution or cancellation
+    SchemaVersion,     // storage layout version, 1 when absent
+    Shutdown,
+    ShutdownClaim(Address),            // tallied final equity, until claimed
+    Market(Symbol),      // MarketState
+    Reserves(Symbol),    // schema v1, folded into Market by `migrate`
+    Funding(Symbol),     // schema v1, folded into Market by `migrate`
+    Account(Address),
+    Collateral(Address), // schema v1, folded into Account by `migrate`
     Position(Address, Symbol),
-    NetOi(Symbol),       // net open interest (longs – shorts)
-    SkewScale(Symbol),   // scale used to normalise skew per market
+    NetOi(Symbol),       // schema v1, folded into Market by `migrate`
+    SkewScale(Symbol),   // schema v1, folded into Market by `migrate`
     CollateralToken,
+    CollateralAssets,                  // Vec<Address> of listed non-USD collateral tokens
+    CollateralAsset(Address),          // CollateralAsset
+    CollateralDeposits(Address),       // Σ balances of a collateral token, against its cap
+    TokenCollateral(Address),          // Map<Address, i128> non-USD balances of a trader
+    MarginMode(Address),
+    LiquidationAuction,
+    LiquidationStart(Address, Symbol), // timestamp the position was first flagged liquidatable
+    Ledger(Address, Symbol),           // TradeLedger
+    Backstop,                          // account that inherits liquidated positions, if any
+    Vault,
+    VaultConfig,
+    LpPosition(Address),
+    MarketLimits(Symbol),
+    MarginTiers(Symbol),
+}
+
+// ---------- Events ----------
+// Each state change publishes one of these as its event data, under a short
+// topic symbol followed by the trader and/or market it concerns. `version` is
+// EVENT_VERSION at emission; it is bumped whenever a field changes meaning.
+
+// DEPOSIT / WITHDRAW, topics (name, trader)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub amount: i128,
+    pub collateral: i128,         // balance after the change
+}
+
+// TK_DEP / TK_WDRAW, topics (name, trader, token)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenCollateralEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub token: Address,
+    pub amount: i128,             // in token units
+    pub balance: i128,            // token balance after the change
+}
+
+// SEIZE, topics (name, trader, token)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SeizeEvent {
+    pub version: u32,
+    pub liquidator: Address,
+    pub trader: Address,
+    pub token: Address,
+    pub repaid: i128,             // USD debt the liquidator took over
+    pub seized: i128,             // token units moved to the liquidator
+    pub written_off: i128,        // debt left without collateral, absorbed by the vault
+}
+
+// LP_DEP / LP_REQ / LP_WDRAW, topics (name, lp)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LpEvent {
+    pub version: u32,
+    pub lp: Address,
+    pub amount: i128,             // tokens in or out, 0 for a request
+    pub shares: i128,             // shares minted, requested or burned
+    pub lp_shares: i128,          // LP's share balance afterwards
+}
+
+// OPEN, topics (name, trader, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OpenEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub size: i128,
+    pub margin: i128,
+    pub fill_price: i128,
+    pub fee: i128,
+    pub funding_paid: i128,       // settled on the existing position
+    pub position: Position,       // position after the trade
+}
+
+// CLOSE, topics (name, trader, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CloseEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub size: i128,
+    pub fill_price: i128,
+    pub pnl: i128,
+    pub fee: i128,
+    pub funding_paid: i128,
+    pub position: Position,       // all zero once fully closed
+}
+
+// ADD_MRGN / RM_MRGN, topics (name, trader, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarginEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub amount: i128,             // positive when added, negative when removed
+    pub position: Position,
+}
+
+// LIQUIDATE, topics (name, trader, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LiquidateEvent {
+    pub version: u32,
+    pub liquidator: Address,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub size: i128,
+    pub mark_price: i128,
+    pub pnl: i128,
+    pub funding_paid: i128,
+    pub bonus: i128,
+    pub remaining_margin: i128,
+    pub backstop: Option<Address>, // account that inherited the position, if any
+}
+
+// FUNDING, topics (name, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FundingEvent {
+    pub version: u32,
+    pub symbol: Symbol,
+    pub delta: i128,
+    pub index: i128,              // cumulative index after the update
+    pub oracle_price: i128,
+    pub mark_price: i128,
+    pub timestamp: u64,
+}
+
+// CONFIG, topics (name, change id): a timelocked change taking effect
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConfigEvent {
+    pub version: u32,
+    pub id: u64,
+    pub proposer: Address,
+    pub change: ParamChange,
+}
+
+// STATUS, topics (name,) for the global status or (name, symbol) for one market
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatusEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub symbol: Option<Symbol>,
+    pub status: MarketStatus,
+}
+
+// OP_PAUSE, topics (name, operation)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PauseEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub operation: Operation,
+    pub paused: bool,
+}
+
+// MRGN_MODE, topics (name, trader)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MarginModeEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub mode: MarginMode,
+}
+
+// LIQ_FLAG, topics (name, trader, symbol): a liquidation auction started
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LiquidationFlagEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub started_at: u64,
+}
+
+// TAKEOVER, topics (name, trader, symbol): the backstop inherited a liquidated position
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TakeoverEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub backstop: Address,
+    pub symbol: Symbol,
+    pub size: i128,
+    pub notional: i128,           // at the mark price
+    pub remaining_margin: i128,   // moved from the trader to the backstop
+    pub position: Position,       // backstop's position afterwards
+}
+
+// SETTLED, topics (name, symbol)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SettleEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub symbol: Symbol,
+    pub price: i128,
+    pub settled_at: u64,
+}
+
+// CLAIM, topics (name, trader, symbol): a position closed at the settlement price
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClaimEvent {
+    pub version: u32,
+    pub trader: Address,
+    pub symbol: Symbol,
+    pub size: i128,
+    pub price: i128,
+    pub pnl: i128,
+    pub funding_paid: i128,
+    pub credited: i128,           // collateral change, negative when debited
+}
+
+// GRANT / REVOKE, topics (name, role)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RoleEvent {
+    pub version: u32,
+    pub owner: Address,
+    pub role: Role,
+    pub account: Address,
+}
+
+// ADM_PROP / ADM_ACPT / ADM_CNCL, topics (name, admin)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AdminEvent {
+    pub version: u32,
+    pub admin: Address,           // owner at the time, the previous one on acceptance
+    pub pending: Address,         // address proposed, accepted or dropped
+}
+
+// SHUTDOWN, topics (name,)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShutdownEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub book: i128,
+    pub assets: i128,
+}
+
+// SD_TALLY, topics (name,): the last account is tallied and claims open
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShutdownTallyEvent {
+    pub version: u32,
+    pub trader_claims: i128,
+    pub vault_nav: i128,
+    pub total_claims: i128,
+    pub assets: i128,
+}
+
+// SD_CLAIM / SD_LP, topics (name, trader or lp)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShutdownClaimEvent {
+    pub version: u32,
+    pub claimant: Address,
+    pub claim: i128,              // equity or NAV share owed
+    pub amount: i128,             // paid out after the pro-rata haircut
+}
+
+// UPGRADE, topics (name,)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UpgradeEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub wasm_hash: BytesN<32>,
+}
+
+// MIGRATE / MIGRATED, topics (name, schema version)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MigrationEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub schema_version: u32,
+    pub migrated: u32,            // entries rewritten by this batch, 0 on completion
+}
+
+// QUEUED / CANCELLED, topics (name, change id)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueueEvent {
+    pub version: u32,
+    pub id: u64,
+    pub caller: Address,          // proposer when queued, guardian when cancelled
+    pub change: ParamChange,
+    pub eta: u64,
+}
+
+// TREASURY, topics (name, token): tokens sent out of the treasury
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreasuryEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub token: Address,
+    pub to: Address,
+    pub amount: i128,
+    pub balance: i128,            // treasury balance of the token afterwards
+}
+
+// BACKSTOP, topics (name,)
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackstopEvent {
+    pub version: u32,
+    pub caller: Address,
+    pub backstop: Option<Address>,
 }
 
 // Oracle types
@@ -139,6 +911,7 @@ trait TokenIfc {
     fn transfer(from: &Address, to: &Address, amount: &i128);
     // transfer_from(spender, from, to, amount)
     fn transfer_from(spender: &Address, from: &Address, to: &Address, amount: &i128);
+    fn balance(id: &Address) -> i128;
 }
 
 #[contract]
@@ -147,12 +920,14 @@ pub struct FlashPerp;
 #[contractimpl]
 impl FlashPerp {
     pub fn initialize(env: Env, admin: Address, token_addr: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
         if env.storage().instance().has(&DataKey::Admin) {
             return Err(Error::AlreadyInitialized);
         }
 
         env.storage().instance().set(&DataKey::Admin, &admin);
-        env.storage().instance().set(&DataKey::Paused, &false);
+        env.storage().instance().set(&DataKey::GlobalStatus, &MarketStatus::Active);
+        env.storage().instance().set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
 
         // Store the actual collateral token contract address provided
         env.storage().instance().set(&DataKey::CollateralToken, &token_addr);
@@ -161,57 +936,48 @@ impl FlashPerp {
         // every pool and size the quote leg to the *current* oracle price so that the pool value
         // starts close to 1 000 USDC for all markets, instead of a hard-coded 1 000 000.
 
-        let symbols = vec![&env, symbol_short!("XLM"), symbol_short!("BTC"), symbol_short!("ETH")];
-        for sym in symbols.iter() {
+        for sym in Self::supported_symbols(&env).iter() {
             let base_init: i128 = 1_000_000_000; // 1 000 units (1e6 precision)
 
             // Try to fetch an oracle price; fall back to 1.0$ if unavailable so init never fails
-            #[cfg(test)]
-            let oracle_p = Self::_mock_oracle_price(sym.clone()).unwrap_or(1_000_000);
-
-            #[cfg(not(test))]
-            let oracle_p = fetch_oracle_price(&env, sym.clone()).unwrap_or(1_000_000);
+            let oracle_p = Self::oracle_price(&env, &sym).unwrap_or(1_000_000);
 
             let quote_init = base_init
                 .checked_mul(oracle_p).ok_or(Error::Overflow)?
                 / DEC_P; // convert back to 1e6 scale
 
-            let reserve = Reserve {
-                base: base_init,
-                quote: quote_init,
+            let market = MarketState {
+                reserve: Reserve {
+                    base: base_init,
+                    quote: quote_init,
+                },
+                funding: FundingData {
+                    index: 0,
+                    last_update: env.ledger().timestamp(),
+                },
+                // Net OI starts at zero for each market
+                net_oi: 0,
+                // --- Parcl-style additions ---
+                // Set skew scale (can be tweaked later via admin fn)
+                skew_scale: default_skew_scale(&sym)?,
+                net_notional: 0,
+                open_interest: OpenInterest { long: 0, short: 0 },
             };
-            env.storage().persistent().set(&DataKey::Reserves(sym.clone()), &reserve);
-            env.storage().persistent().extend_ttl(&DataKey::Reserves(sym.clone()), 10_000, 10_000);
-
-            let funding = FundingData {
-                rate: 0,
-                last_update: env.ledger().timestamp(),
-            };
-            env.storage().persistent().set(&DataKey::Funding(sym.clone()), &funding);
-            env.storage().persistent().extend_ttl(&DataKey::Funding(sym.clone()), 10_000, 10_000);
-
-            // --- Parcl-style additions ---
-            // Set skew scale (can be tweaked later via admin fn)
-            let skew_scale = default_skew_scale(&sym)?;
-            env.storage().persistent().set(&DataKey::SkewScale(sym.clone()), &skew_scale);
-            env.storage().persistent().extend_ttl(&DataKey::SkewScale(sym.clone()), 10_000, 10_000);
-
-            // Net OI starts at zero for each market
-            env.storage().persistent().set(&DataKey::NetOi(sym.clone()), &0i128);
-            env.storage().persistent().extend_ttl(&DataKey::NetOi(sym.clone()), 10_000, 10_000);
+            Self::put_market_state(&env, &sym, &market);
         }
 
         Ok(())
     }
 
     pub fn deposit_collateral(env: Env, trader: Address, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
         trader.require_auth();
         
         if amount <= 0 {
             return Err(Error::InvalidAmount);
         }
 
-        Self::check_not_paused(&env)?;
+        Self::check_operation(&env, Operation::Deposit)?;
 
         // Cross-contract transfer
         #[cfg(not(test))]
@@ -224,32 +990,34 @@ impl FlashPerp {
         let current_collateral = Self::get_collateral(&env, &trader);
         let new_collateral = current_collateral + amount;
 
-        env.storage().persistent().set(&DataKey::Collateral(trader.clone()), &new_collateral);
-        env.storage().persistent().extend_ttl(&DataKey::Collateral(trader.clone()), 10_000, 10_000);
+        Self::put_collateral(&env, &trader, new_collateral);
+        Self::clear_cross_liquidation_starts(&env, &trader);
 
-        env.events().publish((symbol_short!("DEPOSIT"), trader), amount);
+        let event = CollateralEvent { version: EVENT_VERSION, trader: trader.clone(), amount, collateral: new_collateral };
+        env.events().publish((symbol_short!("DEPOSIT"), trader), event);
         Ok(())
     }
 
     pub fn withdraw_collateral(env: Env, trader: Address, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
         trader.require_auth();
 
         if amount <= 0 {
             return Err(Error::InvalidAmount);
         }
 
-        Self::check_not_paused(&env)?;
+        Self::check_operation(&env, Operation::Withdraw)?;
 
         let current_collateral = Self::get_collateral(&env, &trader);
         let free_collateral = Self::calculate_free_collateral(&env, &trader)?;
 
-        if amount > free_collateral {
+        // Token collateral backs margin but is never paid out as USD
+        if amount > free_collateral || amount > current_collateral {
             return Err(Error::InsufficientCollateral);
         }
 
         let new_collateral = current_collateral - amount;
-        env.storage().persistent().set(&DataKey::Collateral(trader.clone()), &new_collateral);
-        env.storage().persistent().extend_ttl(&DataKey::Collateral(trader.clone()), 10_000, 10_000);
+        Self::put_collateral(&env, &trader, new_collateral);
 
         // Transfer back to trader before we move `trader` in the event
         #[cfg(not(test))]
@@ -259,90 +1027,274 @@ impl FlashPerp {
             token.transfer(&env.current_contract_address(), &trader, &amount);
         }
 
-        env.events().publish((symbol_short!("WITHDRAW"), trader), amount);
+        let event = CollateralEvent { version: EVENT_VERSION, trader: trader.clone(), amount, collateral: new_collateral };
+        env.events().publish((symbol_short!("WITHDRAW"), trader), event);
 
         Ok(())
     }
 
-    pub fn open_position(
-        env: Env,
-        trader: Address,
-        symbol: Symbol,
-        size: i128,
-        margin: i128,
-        limit_price: i128,
-    ) -> Result<(), Error> {
+    /// Deposits a listed non-USD collateral token. Its haircut value counts
+    /// towards margin; the balance itself stays in the token.
+    pub fn deposit_token_collateral(env: Env, trader: Address, token: Address, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
         trader.require_auth();
 
-        if size == 0 || margin <= 0 {
+        if amount <= 0 {
             return Err(Error::InvalidAmount);
         }
 
-        Self::check_not_paused(&env)?;
-        Self::validate_symbol(&symbol)?;
+        Self::check_operation(&env, Operation::Deposit)?;
 
-        let mark_price = Self::get_mark_price(&env, &symbol)?;
+        let asset = Self::collateral_asset(&env, &token).ok_or(Error::AssetNotListed)?;
+        let deposits_key = DataKey::CollateralDeposits(token.clone());
+        let deposits: i128 = env.storage().instance().get(&deposits_key).unwrap_or(0);
+        if deposits + amount > asset.deposit_cap {
+            return Err(Error::DepositCapExceeded);
+        }
 
-        // Slippage check
-        if (size > 0 && mark_price > limit_price) || (size < 0 && mark_price < limit_price) {
-            return Err(Error::SlippageExceeded);
+        #[cfg(not(test))]
+        {
+            let collateral_token = Token::new(&env, &token);
+            collateral_token.transfer(&trader, &env.current_contract_address(), &amount);
         }
 
-        // Notional = |size| × price, guard against overflow
-        let notional = size.abs()
-            .checked_mul(mark_price)
-            .ok_or(Error::Overflow)? / DEC_P;
+        env.storage().instance().set(&deposits_key, &(deposits + amount));
+        let balance = Self::token_balance_of(&env, &trader, &token) + amount;
+        Self::put_token_balance(&env, &trader, &token, balance);
+        Self::clear_cross_liquidation_starts(&env, &trader);
+
+        let event = TokenCollateralEvent { version: EVENT_VERSION, trader: trader.clone(), token: token.clone(), amount, balance };
+        env.events().publish((symbol_short!("TK_DEP"), trader, token), event);
+        Ok(())
+    }
 
-        let required_margin = (notional * IMR_BP) / 10_000;
+    /// Withdraws non-USD collateral as long as its haircut value is free.
+    /// Tokens closed to deposits (cap 0) can still be withdrawn.
+    pub fn withdraw_token_collateral(env: Env, trader: Address, token: Address, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
 
-        if margin < required_margin {
-            return Err(Error::InsufficientCollateral);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
         }
 
-        let free_collateral = Self::calculate_free_collateral(&env, &trader)?;
-        if margin > free_collateral {
+        Self::check_operation(&env, Operation::Withdraw)?;
+
+        let current = Self::token_balance_of(&env, &trader, &token);
+        if amount > current {
+            return Err(Error::InsufficientCollateral);
+        }
+        let asset = Self::collateral_asset(&env, &token).ok_or(Error::AssetNotListed)?;
+        let price = Self::oracle_price(&env, &asset.asset)?;
+        if Self::haircut_value(&asset, amount, price)? > Self::calculate_free_collateral(&env, &trader)? {
             return Err(Error::InsufficientCollateral);
         }
 
-        // Update AMM reserves
-        Self::update_reserves(&env, &symbol, size)?;
+        let balance = current - amount;
+        Self::put_token_balance(&env, &trader, &token, balance);
+        let deposits_key = DataKey::CollateralDeposits(token.clone());
+        let deposits: i128 = env.storage().instance().get(&deposits_key).unwrap_or(0);
+        env.storage().instance().set(&deposits_key, &(deposits - amount));
+
+        #[cfg(not(test))]
+        {
+            let collateral_token = Token::new(&env, &token);
+            collateral_token.transfer(&env.current_contract_address(), &trader, &amount);
+        }
 
-        // --- update net OI ---
-        let net_key = DataKey::NetOi(symbol.clone());
-        let current_oi = env.storage().persistent().get::<DataKey, i128>(&net_key).unwrap_or(0);
-        env.storage().persistent().set(&net_key, &(current_oi + size));
-        env.storage().persistent().extend_ttl(&net_key, 10_000, 10_000);
+        let event = TokenCollateralEvent { version: EVENT_VERSION, trader: trader.clone(), token: token.clone(), amount, balance };
+        env.events().publish((symbol_short!("TK_WDRAW"), trader, token), event);
+        Ok(())
+    }
 
-        // Get current funding index
-        let funding = Self::get_funding_data(&env, &symbol);
+    /// Deposits collateral tokens into the vault and mints shares at the
+    /// current NAV per share, or at par into a vault without shares.
+    /// Returns the shares minted.
+    pub fn lp_deposit(env: Env, lp: Address, amount: i128) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        lp.require_auth();
 
-        // Create or update position
-        let position_key = DataKey::Position(trader.clone(), symbol.clone());
-        let existing = env.storage().persistent().get::<DataKey, Position>(&position_key);
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
 
-        let new_position = match existing {
-            Some(mut pos) => {
-                pos.size += size;
-                pos.notional += notional;
-                pos.margin += margin;
-                pos.funding_index = funding.rate;
-                pos
+        Self::check_operation(&env, Operation::LpDeposit)?;
+
+        let mut vault = Self::get_vault(&env);
+        let shares = if vault.total_shares == 0 {
+            // Value left in a vault without shares (e.g. fees earned after the
+            // last LP exited) is not the next depositor's to take: it backs
+            // shares minted 1:1 to no one, so the first LP enters at par.
+            // A deficit would be theirs to cover, so that is refused.
+            let nav = Self::vault_nav(&env, &vault, &mut Map::new(&env))?;
+            if nav < 0 {
+                return Err(Error::InsufficientCollateral);
             }
-            None => Position {
-                size,
-                notional,
-                margin,
-                funding_index: funding.rate,
+            vault.total_shares = nav;
+            amount
+        } else {
+            let nav = Self::vault_nav(&env, &vault, &mut Map::new(&env))?;
+            if nav <= 0 {
+                return Err(Error::InsufficientCollateral);
             }
+            amount.checked_mul(vault.total_shares).ok_or(Error::Overflow)? / nav
         };
+        if shares <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        #[cfg(not(test))]
+        {
+            let token_addr: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+            let token = Token::new(&env, &token_addr);
+            token.transfer(&lp, &env.current_contract_address(), &amount);
+        }
 
-        env.storage().persistent().set(&position_key, &new_position);
-        env.storage().persistent().extend_ttl(&position_key, 10_000, 10_000);
+        vault.balance += amount;
+        vault.total_shares += shares;
+        Self::put_vault(&env, &vault);
 
-        env.events().publish(
-            (symbol_short!("OPEN"), trader, symbol),
-            (size, margin, mark_price)
-        );
+        let mut position = Self::get_lp(&env, &lp);
+        position.shares += shares;
+        position.last_deposit = env.ledger().timestamp();
+        Self::put_lp(&env, &lp, &position);
+
+        let event = LpEvent { version: EVENT_VERSION, lp: lp.clone(), amount, shares, lp_shares: position.shares };
+        env.events().publish((symbol_short!("LP_DEP"), lp), event);
+        Ok(shares)
+    }
+
+    /// Queues `shares` for withdrawal. Allowed once the deposit cooldown has
+    /// passed; replaces any earlier pending request.
+    pub fn lp_request_withdraw(env: Env, lp: Address, shares: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        lp.require_auth();
+
+        Self::check_operation(&env, Operation::LpWithdraw)?;
+
+        let mut position = Self::get_lp(&env, &lp);
+        if shares <= 0 || shares > position.shares {
+            return Err(Error::InvalidAmount);
+        }
+
+        let now = env.ledger().timestamp();
+        if now < position.last_deposit + Self::get_vault_config(&env).deposit_cooldown {
+            return Err(Error::CooldownActive);
+        }
+
+        position.pending_shares = shares;
+        position.requested_at = now;
+        Self::put_lp(&env, &lp, &position);
+
+        let event = LpEvent { version: EVENT_VERSION, lp: lp.clone(), amount: 0, shares, lp_shares: position.shares };
+        env.events().publish((symbol_short!("LP_REQ"), lp), event);
+        Ok(())
+    }
+
+    /// Burns the pending shares after the withdrawal cooldown and pays out
+    /// their value at the current NAV, less trader losses not yet realised.
+    /// Returns the amount paid.
+    pub fn lp_withdraw(env: Env, lp: Address) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        lp.require_auth();
+
+        Self::check_operation(&env, Operation::LpWithdraw)?;
+
+        let mut position = Self::get_lp(&env, &lp);
+        if position.pending_shares == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        if env.ledger().timestamp() < position.requested_at + Self::get_vault_config(&env).withdraw_cooldown {
+            return Err(Error::CooldownActive);
+        }
+
+        // Trader losses are only counted once collected: past an isolated
+        // margin or an account's collateral they never will be, and paying
+        // them out early would leave the remaining LPs short
+        let mut vault = Self::get_vault(&env);
+        let mut oracle_prices = Map::new(&env);
+        let (_, trader_gains, exposure) = Self::vault_exposure(&env, &mut oracle_prices, None)?;
+        let nav = vault.balance - trader_gains;
+        let amount = (position.pending_shares * nav.max(0)) / vault.total_shares;
+        if amount > vault.balance {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        // Withdrawals may not push the remaining pool past its utilization cap
+        if exposure * 10_000 > (nav - amount) * Self::get_vault_config(&env).max_utilization_bp {
+            return Err(Error::UtilizationExceeded);
+        }
+
+        vault.balance -= amount;
+        vault.total_shares -= position.pending_shares;
+        Self::put_vault(&env, &vault);
+
+        let burned = position.pending_shares;
+        position.shares -= burned;
+        position.pending_shares = 0;
+        Self::put_lp(&env, &lp, &position);
+
+        #[cfg(not(test))]
+        {
+            let token_addr: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+            let token = Token::new(&env, &token_addr);
+            token.transfer(&env.current_contract_address(), &lp, &amount);
+        }
+
+        let event = LpEvent { version: EVENT_VERSION, lp: lp.clone(), amount, shares: burned, lp_shares: position.shares };
+        env.events().publish((symbol_short!("LP_WDRAW"), lp), event);
+        Ok(amount)
+    }
+
+    pub fn open_position(
+        env: Env,
+        trader: Address,
+        symbol: Symbol,
+        size: i128,
+        margin: i128,
+        limit_price: i128,
+    ) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
+
+        let plan = Self::plan_open(&env, &trader, &symbol, size, margin, Some(limit_price))?;
+        Self::clear_liquidation_start(&env, &trader, &symbol);
+
+        // Trading fee and funding settled on the existing position go to the
+        // vault, less the protocol's share of the fee
+        let charges = plan.fee + plan.funding_payment;
+        if charges != 0 {
+            let current_collateral = Self::get_collateral(&env, &trader);
+            Self::put_collateral(&env, &trader, current_collateral - charges);
+            Self::adjust_vault_balance(&env, charges);
+            Self::take_protocol_fee(&env, plan.fee);
+        }
+
+        // Update AMM reserves, net OI and open interest
+        let mut market = Self::market_state(&env, &symbol);
+        market.reserve = plan.reserve;
+        market.net_oi = plan.net_oi;
+        market.net_notional = plan.net_notional;
+        market.open_interest = plan.open_interest;
+        Self::put_market_state(&env, &symbol, &market);
+
+        // Create or update position
+        Self::put_position(&env, &trader, &symbol, Some(&plan.position));
+        let volume = (size.abs() * plan.mark_price) / DEC_P;
+        Self::record(&env, &trader, &symbol, Self::ledger_entry(0, plan.fee, plan.funding_payment, 0, volume));
+
+        let event = OpenEvent {
+            version: EVENT_VERSION,
+            trader: trader.clone(),
+            symbol: symbol.clone(),
+            size,
+            margin,
+            fill_price: plan.mark_price,
+            fee: plan.fee,
+            funding_paid: plan.funding_payment,
+            position: plan.position,
+        };
+        env.events().publish((symbol_short!("OPEN"), trader, symbol), event);
 
         Ok(())
     }
@@ -354,448 +1306,4119 @@ impl FlashPerp {
         size: i128,
         limit_price: i128,
     ) -> Result<(), Error> {
+        Self::bump_instance(&env);
         trader.require_auth();
 
-        if size == 0 {
+        let plan = Self::plan_close(&env, &trader, &symbol, size, Some(limit_price))?;
+        Self::clear_liquidation_start(&env, &trader, &symbol);
+
+        // Update AMM reserves, net OI and open interest
+        let mut market = Self::market_state(&env, &symbol);
+        market.reserve = plan.reserve;
+        market.net_oi = plan.net_oi;
+        market.net_notional = plan.net_notional;
+        market.open_interest = plan.open_interest;
+        Self::put_market_state(&env, &symbol, &market);
+
+        // Update position
+        Self::put_position(&env, &trader, &symbol, plan.position.as_ref());
+        let volume = (size.abs() * plan.mark_price) / DEC_P;
+        Self::record(&env, &trader, &symbol, Self::ledger_entry(plan.realized_pnl, plan.fee, plan.funding_payment, 0, volume));
+
+        // Update collateral with PnL, funding and fee, settled against the
+        // vault, which passes the protocol's share of the fee on. Released
+        // margin needs no transfer: it was never moved out of collateral, only reserved.
+        let current_collateral = Self::get_collateral(&env, &trader);
+        Self::put_collateral(&env, &trader, current_collateral + plan.settlement);
+        Self::adjust_vault_balance(&env, -plan.settlement);
+        Self::take_protocol_fee(&env, plan.fee);
+
+        let event = CloseEvent {
+            version: EVENT_VERSION,
+            trader: trader.clone(),
+            symbol: symbol.clone(),
+            size,
+            fill_price: plan.mark_price,
+            pnl: plan.pnl,
+            fee: plan.fee,
+            funding_paid: plan.funding_payment,
+            position: plan.position.unwrap_or(Position {
+                size: 0,
+                notional: 0,
+                margin: 0,
+                funding_index: 0,
+                last_update: 0,
+            }),
+        };
+        env.events().publish((symbol_short!("CLOSE"), trader, symbol), event);
+
+        Ok(())
+    }
+
+    /// Switches the account between isolated and cross margin. Only allowed
+    /// while the account has no open positions.
+    pub fn set_margin_mode(env: Env, trader: Address, mode: MarginMode) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
+
+        Self::check_operation(&env, Operation::MarginMode)?;
+
+        if !Self::account(&env, &trader).markets.is_empty() {
+            return Err(Error::PositionsOpen);
+        }
+
+        let mode_key = DataKey::MarginMode(trader.clone());
+        env.storage().persistent().set(&mode_key, &mode);
+        Self::extend_persistent(&env, &mode_key);
+
+        env.events().publish((symbol_short!("MRGN_MODE"), trader.clone()), MarginModeEvent { version: EVENT_VERSION, trader, mode });
+        Ok(())
+    }
+
+    /// Moves `amount` of free collateral into an existing position's margin.
+    pub fn add_margin(env: Env, trader: Address, symbol: Symbol, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
+
+        if amount <= 0 {
             return Err(Error::InvalidAmount);
         }
 
-        Self::check_not_paused(&env)?;
+        Self::check_market(&env, &symbol, Operation::AddMargin)?;
 
         let position_key = DataKey::Position(trader.clone(), symbol.clone());
-        let mut position = env.storage().persistent()
-            .get::<DataKey, Position>(&position_key)
+        let mut position = Self::load_position(&env, &position_key)
             .ok_or(Error::PositionNotFound)?;
 
-        // Check if closing size exceeds position
-        if size.abs() > position.size.abs() {
-            return Err(Error::InvalidAmount);
+        let free_collateral = Self::calculate_free_collateral(&env, &trader)?;
+        if amount > free_collateral {
+            return Err(Error::InsufficientCollateral);
         }
 
-        let mark_price = Self::get_mark_price(&env, &symbol)?;
-        
-        // Slippage check – for reducing longs want min price, for reducing shorts want max
-        if (size > 0 && mark_price < limit_price) || (size < 0 && mark_price > limit_price) {
-            return Err(Error::SlippageExceeded);
-        }
-        
-        // Calculate PnL for the closing portion
-        let closing_notional = (size.abs() * mark_price) / DEC_P;
-        let original_notional = (position.notional * size.abs()) / position.size.abs();
-        
-        let pnl = if (size > 0 && position.size > 0) || (size < 0 && position.size < 0) {
-            // Closing position (same direction)
-            if position.size > 0 {
-                closing_notional - original_notional
-            } else {
-                original_notional - closing_notional
-            }
-        } else {
-            // Flipping position
-            return Err(Error::InvalidAmount);
-        };
+        position.margin = position.margin.checked_add(amount).ok_or(Error::Overflow)?;
+        position.last_update = env.ledger().timestamp();
+        Self::put_position(&env, &trader, &symbol, Some(&position));
+        Self::clear_liquidation_start(&env, &trader, &symbol);
 
-        // Update AMM reserves
-        Self::update_reserves(&env, &symbol, -size)?;
+        let event = MarginEvent { version: EVENT_VERSION, trader: trader.clone(), symbol: symbol.clone(), amount, position };
+        env.events().publish((symbol_short!("ADD_MRGN"), trader, symbol), event);
 
-        // --- update net OI ---
-        let net_key = DataKey::NetOi(symbol.clone());
-        let cur_oi = env.storage().persistent().get::<DataKey, i128>(&net_key).unwrap_or(0);
-        env.storage().persistent().set(&net_key, &(cur_oi - size));
-        env.storage().persistent().extend_ttl(&net_key, 10_000, 10_000);
+        Ok(())
+    }
 
-        // Calculate funding payment
-        let funding = Self::get_funding_data(&env, &symbol);
-        let funding_payment = Self::calculate_funding_payment(&position, &funding);
+    /// Releases `amount` of a position's margin back to free collateral. The
+    /// position must still meet the initial margin ratio at the current mark.
+    pub fn remove_margin(env: Env, trader: Address, symbol: Symbol, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
 
-        // Update position
-        let margin_released = (position.margin * size.abs()) / position.size.abs();
-        position.size -= size;
-        position.notional -= original_notional;
-        position.margin -= margin_released;
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
 
-        if position.size == 0 {
-            env.storage().persistent().remove(&position_key);
-        } else {
-            position.funding_index = funding.rate;
-            env.storage().persistent().set(&position_key, &position);
-            env.storage().persistent().extend_ttl(&position_key, 10_000, 10_000);
+        Self::check_market(&env, &symbol, Operation::RemoveMargin)?;
+
+        let position_key = DataKey::Position(trader.clone(), symbol.clone());
+        let mut position = Self::load_position(&env, &position_key)
+            .ok_or(Error::PositionNotFound)?;
+
+        if amount >= position.margin {
+            return Err(Error::InsufficientCollateral);
         }
+        position.margin -= amount;
 
-        // Update collateral with PnL and funding
-        let collateral_key = DataKey::Collateral(trader.clone());
-        let current_collateral = Self::get_collateral(&env, &trader);
-        let new_collateral = current_collateral + margin_released + pnl - funding_payment;
-        env.storage().persistent().set(&collateral_key, &new_collateral);
-        env.storage().persistent().extend_ttl(&collateral_key, 10_000, 10_000);
+        let mark_price = Self::get_mark_price(&env, &symbol)?;
+        let funding = Self::get_funding_data(&env, &symbol);
+        let current_notional = (position.size.abs() * mark_price) / DEC_P;
+        let imr_bp = Self::margin_tier(&env, &symbol, current_notional).imr_bp;
+        if Self::calculate_margin_ratio(&position, mark_price, &funding) < imr_bp {
+            return Err(Error::InsufficientCollateral);
+        }
 
-        env.events().publish(
-            (symbol_short!("CLOSE"), trader, symbol),
-            (size, pnl, funding_payment)
-        );
+        position.last_update = env.ledger().timestamp();
+        Self::put_position(&env, &trader, &symbol, Some(&position));
+
+        let event = MarginEvent { version: EVENT_VERSION, trader: trader.clone(), symbol: symbol.clone(), amount: -amount, position };
+        env.events().publish((symbol_short!("RM_MRGN"), trader, symbol), event);
 
         Ok(())
     }
 
+    /// Dry-run of `open_position`: returns what the trade would do at the
+    /// current state, or the error the real call would fail with.
+    pub fn quote_open(
+        env: Env,
+        trader: Address,
+        symbol: Symbol,
+        size: i128,
+        margin: i128,
+    ) -> Result<TradeQuote, Error> {
+        Self::bump_instance(&env);
+        let plan = Self::plan_open(&env, &trader, &symbol, size, margin, None)?;
+        let funding = Self::get_funding_data(&env, &symbol);
+
+        Ok(TradeQuote {
+            fill_price: plan.mark_price,
+            price_impact_bp: Self::price_impact_bp(plan.mark_price, plan.post_mark_price),
+            fee: plan.fee,
+            required_margin: plan.required_margin,
+            margin_ratio: Self::calculate_margin_ratio(&plan.position, plan.post_mark_price, &funding),
+            realized_pnl: 0,
+            funding_payment: plan.funding_payment,
+        })
+    }
+
+    /// Dry-run of `close_position`, see `quote_open`.
+    pub fn quote_close(
+        env: Env,
+        trader: Address,
+        symbol: Symbol,
+        size: i128,
+    ) -> Result<TradeQuote, Error> {
+        Self::bump_instance(&env);
+        let plan = Self::plan_close(&env, &trader, &symbol, size, None)?;
+        let funding = Self::get_funding_data(&env, &symbol);
+        let margin_ratio = match &plan.position {
+            Some(position) => Self::calculate_margin_ratio(position, plan.post_mark_price, &funding),
+            None => 10_000,
+        };
+
+        Ok(TradeQuote {
+            fill_price: plan.mark_price,
+            price_impact_bp: Self::price_impact_bp(plan.mark_price, plan.post_mark_price),
+            fee: plan.fee,
+            required_margin: 0,
+            margin_ratio,
+            realized_pnl: plan.realized_pnl,
+            funding_payment: plan.funding_payment,
+        })
+    }
+
     pub fn liquidate(
         env: Env,
         liquidator: Address,
         trader: Address,
         symbol: Symbol,
     ) -> Result<(), Error> {
+        Self::bump_instance(&env);
         liquidator.require_auth();
 
         if liquidator == trader {
             return Err(Error::SelfLiquidation);
         }
 
-        Self::check_not_paused(&env)?;
+        Self::check_market(&env, &symbol, Operation::Liquidate)?;
 
         let position_key = DataKey::Position(trader.clone(), symbol.clone());
-        let position = env.storage().persistent()
-            .get::<DataKey, Position>(&position_key)
+        let position = Self::load_position(&env, &position_key)
             .ok_or(Error::PositionNotFound)?;
 
-        let mark_price = Self::get_mark_price(&env, &symbol)?;
-        
-        // Check if position is liquidatable
-        let margin_ratio = Self::calculate_margin_ratio(&position, mark_price);
-        if margin_ratio >= MMR_BP {
+        let mut oracle_prices = Map::new(&env);
+        let mark_price = Self::cached_mark_price(&env, &symbol, &mut oracle_prices)?;
+        let funding = Self::get_funding_data(&env, &symbol);
+        let mode = Self::get_margin_mode(&env, &trader);
+
+        // Check if position (isolated) or the whole account (cross) is liquidatable
+        if !Self::is_liquidatable(&env, &trader, &symbol, &position, mark_price, &funding, mode, &mut oracle_prices)? {
             return Err(Error::BelowMaintenanceMargin);
         }
 
-        // Calculate liquidation values
-        let current_notional = (position.size.abs() * mark_price) / DEC_P;
-        let liquidation_bonus = (current_notional * BONUS_BP) / 10_000;
+        let plan = Self::plan_liquidation(&env, &trader, &symbol, &position, mark_price, &funding, mode)?;
+        Self::liquidate_position(&env, &liquidator, &trader, &symbol, &position, mark_price, plan);
 
-        // Update reserves
-        Self::update_reserves(&env, &symbol, -position.size)?;
+        Ok(())
+    }
 
-        // --- update net OI ---
-        let net_key = DataKey::NetOi(symbol.clone());
-        let cur_oi = env.storage().persistent().get::<DataKey, i128>(&net_key).unwrap_or(0);
-        env.storage().persistent().set(&net_key, &(cur_oi - position.size));
-        env.storage().persistent().extend_ttl(&net_key, 10_000, 10_000);
+    /// Starts the liquidation auction for an unhealthy position. Returns false
+    /// (and clears any stale auction) if the position is healthy again.
+    pub fn flag_liquidation(env: Env, trader: Address, symbol: Symbol) -> Result<bool, Error> {
+        Self::bump_instance(&env);
+        Self::check_market(&env, &symbol, Operation::Liquidate)?;
 
-        // Remove position
-        env.storage().persistent().remove(&position_key);
+        let position_key = DataKey::Position(trader.clone(), symbol.clone());
+        let position = Self::load_position(&env, &position_key)
+            .ok_or(Error::PositionNotFound)?;
 
-        // Transfer bonus to liquidator
-        let liquidator_collateral = Self::get_collateral(&env, &liquidator);
-        env.storage().persistent().set(
-            &DataKey::Collateral(liquidator.clone()),
-            &(liquidator_collateral + liquidation_bonus)
-        );
-        env.storage().persistent().extend_ttl(&DataKey::Collateral(liquidator.clone()), 10_000, 10_000);
+        let mut oracle_prices = Map::new(&env);
+        let mark_price = Self::cached_mark_price(&env, &symbol, &mut oracle_prices)?;
+        let funding = Self::get_funding_data(&env, &symbol);
+        let mode = Self::get_margin_mode(&env, &trader);
 
-        env.events().publish(
-            (symbol_short!("LIQUIDATE"), trader, symbol),
-            (position.size, liquidation_bonus, liquidator)
-        );
+        let start_key = DataKey::LiquidationStart(trader.clone(), symbol.clone());
+        if !Self::is_liquidatable(&env, &trader, &symbol, &position, mark_price, &funding, mode, &mut oracle_prices)? {
+            env.storage().persistent().remove(&start_key);
+            return Ok(false);
+        }
 
-        Ok(())
-    }
+        if !env.storage().persistent().has(&start_key) {
+            let now = env.ledger().timestamp();
+            env.storage().persistent().set(&start_key, &now);
+            Self::extend_persistent(&env, &start_key);
+            let event = LiquidationFlagEvent { version: EVENT_VERSION, trader: trader.clone(), symbol: symbol.clone(), started_at: now };
+            env.events().publish((symbol_short!("LIQ_FLAG"), trader, symbol), event);
+        }
 
-    // View functions
-    pub fn get_position(env: Env, trader: Address, symbol: Symbol) -> Option<Position> {
-        let position_key = DataKey::Position(trader, symbol);
-        env.storage().persistent().get(&position_key)
+        Ok(true)
     }
 
-    pub fn get_free_collateral(env: Env, trader: Address) -> Result<i128, Error> {
-        Self::calculate_free_collateral(&env, &trader)
+    /// Current liquidation bonus in bp for a position, following the auction
+    /// schedule from when it was flagged (the minimum if it is not flagged).
+    pub fn get_liquidation_bonus_bp(env: Env, trader: Address, symbol: Symbol) -> i128 {
+        Self::bump_instance(&env);
+        Self::current_bonus_bp(&env, &trader, &symbol)
     }
 
-    pub fn get_mark_price_view(env: Env, symbol: Symbol) -> Result<i128, Error> {
-        Self::get_mark_price(&env, &symbol)
-    }
+    /// Liquidates every unhealthy `(trader, symbol)` entry in one call. Healthy
+    /// or already-closed entries are skipped rather than failing the batch,
+    /// and each market's oracle price is fetched at most once.
+    pub fn liquidate_batch(
+        env: Env,
+        liquidator: Address,
+        entries: Vec<(Address, Symbol)>,
+    ) -> Result<Vec<LiquidationResult>, Error> {
+        Self::bump_instance(&env);
+        liquidator.require_auth();
 
-    pub fn get_oracle_price(env: Env, symbol: Symbol) -> Result<i128, Error> {
-        fetch_oracle_price(&env, symbol)
-    }
+        Self::check_operation(&env, Operation::Liquidate)?;
 
-    // Admin functions
-    pub fn pause(env: Env) -> Result<(), Error> {
-        let admin = Self::get_admin(&env)?;
-        admin.require_auth();
-        
-        env.storage().instance().set(&DataKey::Paused, &true);
-        env.events().publish((symbol_short!("PAUSE"),), admin);
-        Ok(())
-    }
+        let mut oracle_prices = Map::new(&env);
+        let mut results = Vec::new(&env);
 
-    pub fn unpause(env: Env) -> Result<(), Error> {
-        let admin = Self::get_admin(&env)?;
-        admin.require_auth();
-        
-        env.storage().instance().set(&DataKey::Paused, &false);
-        env.events().publish((symbol_short!("UNPAUSE"),), admin);
-        Ok(())
-    }
+        for (trader, symbol) in entries {
+            if trader == liquidator {
+                results.push_back(LiquidationResult::Failed(Error::SelfLiquidation as u32));
+                continue;
+            }
 
-    // ------------------------------------------------------------------
-    // Admin-only setter for collateral token after deployment (optional)
-    // ------------------------------------------------------------------
-    pub fn set_collateral_token(env: Env, admin: Address, token_addr: Address) -> Result<(), Error> {
-        admin.require_auth();
-        let stored_admin = Self::get_admin(&env)?;
-        if admin != stored_admin {
-            return Err(Error::Unauthorized);
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            let position = match Self::load_position(&env, &position_key) {
+                Some(p) => p,
+                None => {
+                    results.push_back(LiquidationResult::NoPosition);
+                    continue;
+                }
+            };
+
+            let outcome = Self::check_market(&env, &symbol, Operation::Liquidate)
+                .and_then(|_| Self::cached_mark_price(&env, &symbol, &mut oracle_prices))
+                .and_then(|mark_price| {
+                    let funding = Self::get_funding_data(&env, &symbol);
+                    let mode = Self::get_margin_mode(&env, &trader);
+                    if !Self::is_liquidatable(&env, &trader, &symbol, &position, mark_price, &funding, mode, &mut oracle_prices)? {
+                        // Recovered since it was flagged: the next dip starts a fresh auction
+                        Self::clear_liquidation_start(&env, &trader, &symbol);
+                        return Ok(LiquidationResult::Healthy);
+                    }
+                    // Plan before writing anything so a failed entry leaves no partial state
+                    let plan = Self::plan_liquidation(&env, &trader, &symbol, &position, mark_price, &funding, mode)?;
+                    let bonus = Self::liquidate_position(&env, &liquidator, &trader, &symbol, &position, mark_price, plan);
+                    Ok(LiquidationResult::Liquidated(bonus))
+                });
+
+            results.push_back(outcome.unwrap_or_else(|e| LiquidationResult::Failed(e as u32)));
         }
-        env.storage().instance().set(&DataKey::CollateralToken, &token_addr);
-        Ok(())
+
+        Ok(results)
     }
 
-    // Internal helper functions
-    fn get_admin(env: &Env) -> Result<Address, Error> {
+    /// Liquidates a cross-margined account whose equity is below its total
+    /// maintenance margin. Positions are closed largest maintenance
+    /// requirement first until the account is healthy again; the liquidator
+    /// earns the bonus on each closed notional. Positions in halted or
+    /// settled markets are left in place, and the call fails with that
+    /// market's error when nothing else is open. Returns the closed markets.
+    pub fn liquidate_account(
+        env: Env,
+        liquidator: Address,
+        trader: Address,
+    ) -> Result<Vec<Symbol>, Error> {
+        Self::bump_instance(&env);
+        liquidator.require_auth();
+
+        if liquidator == trader {
+            return Err(Error::SelfLiquidation);
+        }
+
+        Self::check_operation(&env, Operation::Liquidate)?;
+
+        if Self::get_margin_mode(&env, &trader) != MarginMode::Cross {
+            return Err(Error::NotCrossMargin);
+        }
+
+        let (equity, maintenance_margin) = Self::calculate_account_health(&env, &trader)?;
+        if equity >= maintenance_margin {
+            return Err(Error::BelowMaintenanceMargin);
+        }
+
+        // (symbol, maintenance margin contribution) of every open position
+        let mut open = Vec::new(&env);
+        let mut oracle_prices = Map::new(&env);
+        let mut skipped = None;
+        for symbol in Self::account(&env, &trader).markets {
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            if let Some(position) = Self::load_position(&env, &position_key) {
+                // Settled positions count towards health but leave via
+                // `claim_settlement`; those in halted markets wait for them to reopen
+                if let Err(e) = Self::check_market(&env, &symbol, Operation::Liquidate) {
+                    skipped = Some(e);
+                    continue;
+                }
+                let mark_price = Self::cached_mark_price(&env, &symbol, &mut oracle_prices)?;
+                let contribution = Self::maintenance_margin(&env, &symbol, &position, mark_price);
+                open.push_back((symbol, contribution));
+            }
+        }
+        if open.is_empty() {
+            return Err(skipped.unwrap_or(Error::PositionNotFound));
+        }
+
+        let mut closed = Vec::new(&env);
+        while !open.is_empty() {
+            let mut riskiest = 0u32;
+            for i in 1..open.len() {
+                if open.get_unchecked(i).1 > open.get_unchecked(riskiest).1 {
+                    riskiest = i;
+                }
+            }
+            let (symbol, _) = open.get_unchecked(riskiest);
+            open.remove(riskiest);
+
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            let position = Self::load_position(&env, &position_key)
+                .ok_or(Error::PositionNotFound)?;
+            let mark_price = Self::cached_mark_price(&env, &symbol, &mut oracle_prices)?;
+            let funding = Self::get_funding_data(&env, &symbol);
+
+            let plan = Self::plan_liquidation(&env, &trader, &symbol, &position, mark_price, &funding, MarginMode::Cross)?;
+            Self::liquidate_position(&env, &liquidator, &trader, &symbol, &position, mark_price, plan);
+            closed.push_back(symbol);
+
+            let (equity, maintenance_margin) = Self::calculate_account_health(&env, &trader)?;
+            if equity >= maintenance_margin {
+                break;
+            }
+        }
+
+        Ok(closed)
+    }
+
+    /// Repays up to `repay` of an unhealthy account's USD debt from the
+    /// liquidator's collateral, in exchange for the trader's `token` collateral
+    /// at the oracle price less the maximum liquidation bonus. Debt left once
+    /// the trader holds no more tokens is written off against the vault.
+    /// Returns the token units seized.
+    pub fn liquidate_collateral(
+        env: Env,
+        liquidator: Address,
+        trader: Address,
+        token: Address,
+        repay: i128,
+    ) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        liquidator.require_auth();
+
+        if liquidator == trader {
+            return Err(Error::SelfLiquidation);
+        }
+        if repay <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        Self::check_operation(&env, Operation::Liquidate)?;
+
+        let debt = -Self::get_collateral(&env, &trader);
+        let mut oracle_prices = Map::new(&env);
+        let (equity, maintenance_margin) = Self::calculate_account_health_cached(&env, &trader, &mut oracle_prices)?;
+        if debt <= 0 || equity >= maintenance_margin {
+            return Err(Error::BelowMaintenanceMargin);
+        }
+
+        let balance = Self::token_balance_of(&env, &trader, &token);
+        if balance == 0 {
+            return Err(Error::InsufficientCollateral);
+        }
+        let asset = Self::collateral_asset(&env, &token).ok_or(Error::AssetNotListed)?;
+        let price = Self::cached_oracle_price(&env, &asset.asset, &mut oracle_prices)?;
+        let bonus_bp = Self::get_auction(&env).max_bonus_bp;
+
+        // Never repay more than the whole balance is worth after the bonus;
+        // at that point the whole balance goes, rounding dust included
+        let max_repay = (Self::token_value(&asset, balance, price)? * 10_000) / (10_000 + bonus_bp);
+        let (repaid, seized) = if repay.min(debt) >= max_repay {
+            (max_repay, balance)
+        } else {
+            let repaid = repay.min(debt);
+            let seized = repaid.checked_mul(10_000 + bonus_bp).ok_or(Error::Overflow)?
+                .checked_mul(10i128.pow(asset.decimals)).ok_or(Error::Overflow)?
+                / (10_000 * price);
+            (repaid, seized.min(balance))
+        };
+        if seized <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let liquidator_collateral = Self::get_collateral(&env, &liquidator);
+        if repaid > liquidator_collateral || repaid > Self::calculate_free_collateral(&env, &liquidator)? {
+            return Err(Error::InsufficientCollateral);
+        }
+        Self::put_collateral(&env, &liquidator, liquidator_collateral - repaid);
+        Self::put_token_balance(&env, &liquidator, &token, Self::token_balance_of(&env, &liquidator, &token) + seized);
+        Self::put_token_balance(&env, &trader, &token, balance - seized);
+
+        let mut written_off = 0i128;
+        let collateral = repaid - debt;
+        if collateral < 0 && Self::token_collateral(&env, &trader).is_empty() {
+            written_off = -collateral;
+            Self::adjust_vault_balance(&env, collateral);
+            Self::put_collateral(&env, &trader, 0);
+        } else {
+            Self::put_collateral(&env, &trader, collateral);
+        }
+
+        let event = SeizeEvent {
+            version: EVENT_VERSION,
+            liquidator,
+            trader: trader.clone(),
+            token: token.clone(),
+            repaid,
+            seized,
+            written_off,
+        };
+        env.events().publish((symbol_short!("SEIZE"), trader, token), event);
+        Ok(seized)
+    }
+
+    /// Sends tokens the protocol owns, i.e. its share of trading fees and
+    /// collateral seized at shutdown, to `to`
+    pub fn withdraw_treasury(env: Env, caller: Address, token: Address, to: Address, amount: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Treasurer)?;
+        if amount <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let balance = Self::treasury(&env).get(token.clone()).unwrap_or(0);
+        if amount > balance {
+            return Err(Error::InsufficientCollateral);
+        }
+        Self::credit_treasury(&env, &token, -amount);
+
+        #[cfg(not(test))]
+        {
+            let treasury_token = Token::new(&env, &token);
+            treasury_token.transfer(&env.current_contract_address(), &to, &amount);
+        }
+
+        let event = TreasuryEvent { version: EVENT_VERSION, caller, token: token.clone(), to, amount, balance: balance - amount };
+        env.events().publish((symbol_short!("TREASURY"), token), event);
+        Ok(())
+    }
+
+    // View functions
+    pub fn get_position(env: Env, trader: Address, symbol: Symbol) -> Option<Position> {
+        Self::bump_instance(&env);
+        Self::load_position(&env, &DataKey::Position(trader, symbol))
+    }
+
+    pub fn get_token_collateral(env: Env, trader: Address) -> Map<Address, i128> {
+        Self::bump_instance(&env);
+        Self::token_collateral(&env, &trader)
+    }
+
+    /// Every listed non-USD collateral token with its parameters
+    pub fn get_collateral_assets(env: Env) -> Map<Address, CollateralAsset> {
+        Self::bump_instance(&env);
+        let mut assets = Map::new(&env);
+        for token in Self::collateral_assets(&env) {
+            if let Some(asset) = Self::collateral_asset(&env, &token) {
+                assets.set(token, asset);
+            }
+        }
+        assets
+    }
+
+    pub fn get_trade_ledger(env: Env, trader: Address, symbol: Symbol) -> TradeLedger {
+        Self::bump_instance(&env);
+        Self::trade_ledger(&env, &trader, &symbol)
+    }
+
+    /// Realized figures of every market the trader has traded in
+    pub fn get_trade_ledgers(env: Env, trader: Address) -> Map<Symbol, TradeLedger> {
+        Self::bump_instance(&env);
+        let mut ledgers = Map::new(&env);
+        for symbol in Self::supported_symbols(&env) {
+            let key = DataKey::Ledger(trader.clone(), symbol.clone());
+            if let Some(ledger) = env.storage().persistent().get::<DataKey, TradeLedger>(&key) {
+                ledgers.set(symbol, ledger);
+            }
+        }
+        ledgers
+    }
+
+    pub fn get_market_stats(env: Env, symbol: Symbol) -> Result<MarketStats, Error> {
+        Self::bump_instance(&env);
+        Self::validate_symbol(&symbol)?;
+        let MarketState { funding, net_oi, skew_scale, open_interest, .. } = Self::market_state(&env, &symbol);
+        let oracle_price = Self::oracle_price(&env, &symbol)?;
+        let mark_price = match Self::get_settlement(&env, &symbol) {
+            Some(settlement) => settlement.price,
+            None => Self::compute_mark_price(oracle_price, net_oi, skew_scale),
+        };
+
+        Ok(MarketStats {
+            long_oi: open_interest.long,
+            short_oi: open_interest.short,
+            net_oi,
+            long_oi_value: open_interest.long.checked_mul(mark_price).ok_or(Error::Overflow)? / DEC_P,
+            short_oi_value: open_interest.short.checked_mul(mark_price).ok_or(Error::Overflow)? / DEC_P,
+            skew_scale,
+            skew_bp: Self::price_impact_bp(oracle_price, mark_price),
+            oracle_price,
+            mark_price,
+            funding_index: funding.index,
+            funding_velocity: Self::funding_delta(oracle_price, mark_price, 86_400)?,
+            funding_last_update: funding.last_update,
+            limits: Self::get_market_limits(&env, &symbol),
+            status: Self::effective_status(&env, &symbol),
+        })
+    }
+
+    pub fn get_vault_view(env: Env) -> Result<VaultView, Error> {
+        Self::bump_instance(&env);
+        let vault = Self::get_vault(&env);
+        let (trader_pnl, _, exposure) = Self::vault_exposure(&env, &mut Map::new(&env), None)?;
+        let nav = vault.balance - trader_pnl;
+        let share_price = if vault.total_shares == 0 {
+            DEC_P
+        } else {
+            (nav.max(0) * DEC_P) / vault.total_shares
+        };
+
+        Ok(VaultView {
+            balance: vault.balance,
+            nav,
+            total_shares: vault.total_shares,
+            share_price,
+            exposure,
+            max_exposure: (nav.max(0) * Self::get_vault_config(&env).max_utilization_bp) / 10_000,
+        })
+    }
+
+    pub fn get_lp_position(env: Env, lp: Address) -> LpPosition {
+        Self::bump_instance(&env);
+        Self::get_lp(&env, &lp)
+    }
+
+    pub fn get_margin_mode_view(env: Env, trader: Address) -> MarginMode {
+        Self::bump_instance(&env);
+        Self::get_margin_mode(&env, &trader)
+    }
+
+    pub fn get_free_collateral(env: Env, trader: Address) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        Self::calculate_free_collateral(&env, &trader)
+    }
+
+    pub fn get_mark_price_view(env: Env, symbol: Symbol) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        Self::get_mark_price(&env, &symbol)
+    }
+
+    pub fn get_oracle_price(env: Env, symbol: Symbol) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        fetch_oracle_price(&env, symbol)
+    }
+
+    /// Collateral, free collateral and every open position valued at the
+    /// current mark, using the same margin maths as `liquidate`.
+    pub fn get_account(env: Env, trader: Address) -> Result<AccountView, Error> {
+        Self::bump_instance(&env);
+        let mode = Self::get_margin_mode(&env, &trader);
+        let (equity, maintenance_margin) = Self::calculate_account_health(&env, &trader)?;
+        let account = Self::account(&env, &trader);
+        let mut positions = Vec::new(&env);
+
+        for symbol in account.markets.iter() {
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            let position = match Self::load_position(&env, &position_key) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let mark_price = Self::get_mark_price(&env, &symbol)?;
+            let funding = Self::get_funding_data(&env, &symbol);
+            let unrealized_pnl = Self::calculate_unrealized_pnl(&position, mark_price);
+            let accrued_funding = Self::calculate_funding_payment(&position, &funding);
+            let tier = Self::margin_tier(&env, &symbol, (position.size.abs() * mark_price) / DEC_P);
+
+            // A cross position is backed by the whole account, so its liquidation
+            // price uses account equity net of the other positions' PnL and MMR,
+            // holding their marks constant.
+            let liquidation_price = match mode {
+                MarginMode::Isolated => Self::calculate_liquidation_price(&position, &funding, tier.mmr_bp),
+                MarginMode::Cross => {
+                    let own_maintenance = Self::maintenance_margin(&env, &symbol, &position, mark_price);
+                    let mut backed = position.clone();
+                    backed.margin = equity - (unrealized_pnl - accrued_funding)
+                        - (maintenance_margin - own_maintenance);
+                    Self::calculate_liquidation_price(&backed, &funding, tier.mmr_bp)
+                }
+            };
+
+            positions.push_back(PositionView {
+                symbol,
+                size: position.size,
+                notional: position.notional,
+                margin: position.margin,
+                entry_price: (position.notional * DEC_P) / position.size.abs(),
+                mark_price,
+                unrealized_pnl,
+                accrued_funding,
+                margin_ratio: Self::calculate_margin_ratio(&position, mark_price, &funding),
+                imr_bp: tier.imr_bp,
+                mmr_bp: tier.mmr_bp,
+                liquidation_price,
+                last_update: position.last_update,
+            });
+        }
+
+        Ok(AccountView {
+            margin_mode: mode,
+            collateral: account.collateral,
+            tokens: Self::token_collateral(&env, &trader),
+            token_value: Self::token_collateral_value(&env, &trader, &mut Map::new(&env))?,
+            margin_used: account.margin_used,
+            free_collateral: Self::calculate_free_collateral(&env, &trader)?,
+            equity,
+            maintenance_margin,
+            positions,
+        })
+    }
+
+    // ---------------- Access control ----------------
+    // The owner is the `Admin` address; it holds every role implicitly and is
+    // the only one who can grant or revoke the others.
+    pub fn grant_role(env: Env, owner: Address, role: Role, account: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &owner, Role::Owner)?;
+        if role == Role::Owner {
+            return Err(Error::InvalidRole);
+        }
+        let mut members = Self::role_members(&env, role);
+        if !members.contains(&account) {
+            members.push_back(account.clone());
+            Self::put_role_members(&env, role, &members);
+        }
+        env.events().publish((symbol_short!("GRANT"), role), RoleEvent { version: EVENT_VERSION, owner, role, account });
+        Ok(())
+    }
+
+    pub fn revoke_role(env: Env, owner: Address, role: Role, account: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &owner, Role::Owner)?;
+        if role == Role::Owner {
+            return Err(Error::InvalidRole);
+        }
+        let mut members = Self::role_members(&env, role);
+        if let Some(index) = members.first_index_of(&account) {
+            members.remove(index);
+            Self::put_role_members(&env, role, &members);
+        }
+        env.events().publish((symbol_short!("REVOKE"), role), RoleEvent { version: EVENT_VERSION, owner, role, account });
+        Ok(())
+    }
+
+    pub fn has_role(env: Env, role: Role, account: Address) -> bool {
+        Self::bump_instance(&env);
+        Self::check_role(&env, role, &account)
+    }
+
+    // Explicit members only; the owner is listed under `Role::Owner`
+    pub fn get_role_members(env: Env, role: Role) -> Result<Vec<Address>, Error> {
+        Self::bump_instance(&env);
+        match role {
+            Role::Owner => Ok(vec![&env, Self::get_admin(&env)?]),
+            _ => Ok(Self::role_members(&env, role)),
+        }
+    }
+
+    /// First step of an ownership transfer. Nothing changes until the
+    /// proposed address calls `accept_admin`; a new proposal replaces the old.
+    pub fn propose_admin(env: Env, owner: Address, new_admin: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &owner, Role::Owner)?;
+        env.storage().instance().set(&DataKey::PendingAdmin, &new_admin);
+        let event = AdminEvent { version: EVENT_VERSION, admin: owner.clone(), pending: new_admin };
+        env.events().publish((symbol_short!("ADM_PROP"), owner), event);
+        Ok(())
+    }
+
+    pub fn accept_admin(env: Env, new_admin: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        new_admin.require_auth();
+        let pending: Address = env.storage().instance()
+            .get(&DataKey::PendingAdmin)
+            .ok_or(Error::NoPendingAdmin)?;
+        if pending != new_admin {
+            return Err(Error::Unauthorized);
+        }
+        let previous = Self::get_admin(&env)?;
+        env.storage().instance().set(&DataKey::Admin, &new_admin);
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+        let event = AdminEvent { version: EVENT_VERSION, admin: previous.clone(), pending: new_admin };
+        env.events().publish((symbol_short!("ADM_ACPT"), previous), event);
+        Ok(())
+    }
+
+    pub fn cancel_admin_transfer(env: Env, owner: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &owner, Role::Owner)?;
+        let pending: Address = env.storage().instance()
+            .get(&DataKey::PendingAdmin)
+            .ok_or(Error::NoPendingAdmin)?;
+        env.storage().instance().remove(&DataKey::PendingAdmin);
+        let event = AdminEvent { version: EVENT_VERSION, admin: owner.clone(), pending };
+        env.events().publish((symbol_short!("ADM_CNCL"), owner), event);
+        Ok(())
+    }
+
+    pub fn get_admin_view(env: Env) -> Result<Address, Error> {
+        Self::bump_instance(&env);
+        Self::get_admin(&env)
+    }
+
+    pub fn get_pending_admin(env: Env) -> Option<Address> {
+        Self::bump_instance(&env);
+        env.storage().instance().get(&DataKey::PendingAdmin)
+    }
+
+    // Admin functions
+    // Emergency stop: halts every market (same as `set_global_status(Halted)`)
+    pub fn pause(env: Env, caller: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Guardian)?;
+        
+        env.storage().instance().set(&DataKey::GlobalStatus, &MarketStatus::Halted);
+        let event = StatusEvent { version: EVENT_VERSION, caller, symbol: None, status: MarketStatus::Halted };
+        env.events().publish((symbol_short!("STATUS"),), event);
+        Ok(())
+    }
+
+    pub fn unpause(env: Env, caller: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+        
+        env.storage().instance().set(&DataKey::GlobalStatus, &MarketStatus::Active);
+        let event = StatusEvent { version: EVENT_VERSION, caller, symbol: None, status: MarketStatus::Active };
+        env.events().publish((symbol_short!("STATUS"),), event);
+        Ok(())
+    }
+
+    // Guardians may only tighten the status; loosening it takes the owner
+    pub fn set_global_status(env: Env, caller: Address, status: MarketStatus) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        let current = Self::get_global_status(&env);
+        Self::require_role(&env, &caller, Self::status_change_role(current, status))?;
+        env.storage().instance().set(&DataKey::GlobalStatus, &status);
+        env.events().publish((symbol_short!("STATUS"),), StatusEvent { version: EVENT_VERSION, caller, symbol: None, status });
+        Ok(())
+    }
+
+    /// Set one market's own status, e.g. `ReduceOnly` to stop new exposure
+    /// while closes and liquidations continue.
+    pub fn set_market_status(env: Env, caller: Address, symbol: Symbol, status: MarketStatus) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::validate_symbol(&symbol)?;
+        let key = DataKey::MarketStatus(symbol.clone());
+        let current = env.storage().persistent().get(&key).unwrap_or(MarketStatus::Active);
+        Self::require_role(&env, &caller, Self::status_change_role(current, status))?;
+        env.storage().persistent().set(&key, &status);
+        Self::extend_persistent(&env, &key);
+        let event = StatusEvent { version: EVENT_VERSION, caller, symbol: Some(symbol.clone()), status };
+        env.events().publish((symbol_short!("STATUS"), symbol), event);
+        Ok(())
+    }
+
+    /// Delist a market at a final price, which must lie within
+    /// SETTLEMENT_BAND_BP of the oracle. Every operation on the market is
+    /// refused afterwards except `claim_settlement`.
+    pub fn settle_market(env: Env, caller: Address, symbol: Symbol, price: i128) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+        Self::validate_symbol(&symbol)?;
+        if Self::get_settlement(&env, &symbol).is_some() {
+            return Err(Error::MarketSettled);
+        }
+        if price <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let oracle_price = Self::oracle_price(&env, &symbol)?;
+        if (price - oracle_price).abs() * 10_000 > oracle_price * SETTLEMENT_BAND_BP {
+            return Err(Error::PriceOutOfBounds);
+        }
+
+        let settlement = MarketSettlement { price, settled_at: env.ledger().timestamp() };
+        let key = DataKey::Settlement(symbol.clone());
+        env.storage().persistent().set(&key, &settlement);
+        Self::extend_persistent(&env, &key);
+        let event = SettleEvent {
+            version: EVENT_VERSION,
+            caller,
+            symbol: symbol.clone(),
+            price,
+            settled_at: settlement.settled_at,
+        };
+        env.events().publish((symbol_short!("SETTLED"), symbol), event);
+        Ok(())
+    }
+
+    pub fn get_settlement_view(env: Env, symbol: Symbol) -> Option<MarketSettlement> {
+        Self::bump_instance(&env);
+        Self::get_settlement(&env, &symbol)
+    }
+
+    /// Close the caller's position in a settled market at the settlement
+    /// price, moving PnL net of accrued funding into collateral. Isolated
+    /// losses stop at the position margin. A loss collateral cannot cover stays
+    /// as debt while the trader holds token collateral, and is absorbed by the
+    /// vault otherwise. Returns the amount credited (negative = debited).
+    pub fn claim_settlement(env: Env, trader: Address, symbol: Symbol) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
+
+        Self::check_operation(&env, Operation::Close)?;
+        let settlement = Self::get_settlement(&env, &symbol).ok_or(Error::MarketNotSettled)?;
+
+        let position_key = DataKey::Position(trader.clone(), symbol.clone());
+        let position = Self::load_position(&env, &position_key)
+            .ok_or(Error::PositionNotFound)?;
+
+        let funding = Self::get_funding_data(&env, &symbol);
+        let pnl = Self::calculate_unrealized_pnl(&position, settlement.price);
+        let funding_payment = Self::calculate_funding_payment(&position, &funding);
+        let owed = pnl - funding_payment;
+        let mut delta = owed;
+        if Self::get_margin_mode(&env, &trader) == MarginMode::Isolated {
+            delta = delta.max(-position.margin);
+        }
+
+        let mut market = Self::market_state(&env, &symbol);
+        market.net_oi -= position.size;
+        market.net_notional -= Self::signed_notional(position.size, position.notional);
+        market.open_interest = Self::open_interest_after(&market.open_interest, position.size, 0);
+        Self::put_market_state(&env, &symbol, &market);
+        Self::put_position(&env, &trader, &symbol, None);
+        Self::clear_liquidation_start(&env, &trader, &symbol);
+
+        // As on liquidation, a shortfall stays as debt while token collateral
+        // can cover it; otherwise the vault absorbs it
+        let collateral = Self::get_collateral(&env, &trader);
+        let mut new_collateral = collateral + delta;
+        if Self::token_collateral(&env, &trader).is_empty() {
+            new_collateral = new_collateral.max(0);
+        }
+        Self::put_collateral(&env, &trader, new_collateral);
+        let credited = new_collateral - collateral;
+        Self::adjust_vault_balance(&env, -credited);
+
+        // Only the part of the loss that left the trader's collateral is realized
+        let volume = (position.size.abs() * settlement.price) / DEC_P;
+        let realized_pnl = pnl + credited - owed;
+        Self::record(&env, &trader, &symbol, Self::ledger_entry(realized_pnl, 0, funding_payment, 0, volume));
+
+        let event = ClaimEvent {
+            version: EVENT_VERSION,
+            trader: trader.clone(),
+            symbol: symbol.clone(),
+            size: position.size,
+            price: settlement.price,
+            pnl,
+            funding_paid: funding_payment,
+            credited,
+        };
+        env.events().publish((symbol_short!("CLAIM"), trader, symbol), event);
+
+        Ok(credited)
+    }
+
+    /// Irreversibly stops the protocol. Prices are snapshotted and every
+    /// trading, margin and vault entrypoint is refused from here on;
+    /// `tally_shutdown` then closes every account, after which traders and
+    /// LPs exit through `claim_shutdown` and `lp_claim_shutdown`.
+    /// `fallback_prices` stand in for the oracle price of any market whose
+    /// feed cannot be read, so a dead feed cannot block the shutdown.
+    pub fn shutdown(env: Env, caller: Address, fallback_prices: Map<Symbol, i128>) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+        if env.storage().instance().has(&DataKey::Shutdown) {
+            return Err(Error::ShutDown);
+        }
+
+        let mut oracle_prices = Map::new(&env);
+        let mut prices = Map::new(&env);
+        for symbol in Self::supported_symbols(&env) {
+            if Self::get_settlement(&env, &symbol).is_none() {
+                Self::shutdown_oracle_price(&env, &symbol, &fallback_prices, &mut oracle_prices)?;
+            }
+            let price = Self::cached_mark_price(&env, &symbol, &mut oracle_prices)?;
+            prices.set(symbol, price);
+        }
+        let mut collateral_prices = Map::new(&env);
+        for token in Self::collateral_assets(&env) {
+            if let Some(asset) = Self::collateral_asset(&env, &token) {
+                let price = Self::shutdown_oracle_price(&env, &asset.asset, &fallback_prices, &mut oracle_prices)?;
+                collateral_prices.set(token, price);
+            }
+        }
+
+        // The treasury's share of the collateral token is the protocol's, not a claim
+        let token_addr: Address = env.storage().instance().get(&DataKey::CollateralToken).ok_or(Error::NotInitialized)?;
+        let treasury = Self::treasury(&env).get(token_addr.clone()).unwrap_or(0);
+        let vault = Self::get_vault(&env);
+        let state = ShutdownState {
+            shutdown_at: env.ledger().timestamp(),
+            prices,
+            collateral_prices,
+            book: env.storage().instance().get::<DataKey, i128>(&DataKey::TotalCollateral).unwrap_or(0) + vault.balance,
+            assets: Token::new(&env, &token_addr).balance(&env.current_contract_address()) - treasury,
+            trader_claims: 0,
+            tallied: false,
+            vault_nav: 0,
+            total_claims: 0,
+            total_shares: vault.total_shares,
+            paid: 0,
+        };
+        env.storage().instance().set(&DataKey::Shutdown, &state);
+        let event = ShutdownEvent { version: EVENT_VERSION, caller, book: state.book, assets: state.assets };
+        env.events().publish((symbol_short!("SHUTDOWN"),), event);
+        Ok(())
+    }
+
+    pub fn get_shutdown(env: Env) -> Option<ShutdownState> {
+        Self::bump_instance(&env);
+        env.storage().instance().get(&DataKey::Shutdown)
+    }
+
+    /// Final equity of a trader at the shutdown prices, before any pro-rata haircut
+    pub fn get_shutdown_equity(env: Env, trader: Address) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        let state = Self::get_shutdown_state(&env)?;
+        if let Some(claim) = env.storage().persistent().get(&DataKey::ShutdownClaim(trader.clone())) {
+            return Ok(claim);
+        }
+        Ok(Self::shutdown_equity(&env, &state, &trader))
+    }
+
+    /// Closes every position of each listed trader at the shutdown prices and
+    /// records their final equity as their claim. Permissionless; accounts
+    /// already tallied or holding nothing are skipped, so batches can overlap.
+    /// Once no collateral, token deposit or open interest is left, the vault
+    /// NAV is fixed as what remains of the book and claims open. Returns the
+    /// number of accounts tallied.
+    pub fn tally_shutdown(env: Env, traders: Vec<Address>) -> Result<u32, Error> {
+        Self::bump_instance(&env);
+        let mut state = Self::get_shutdown_state(&env)?;
+
+        let mut tallied = 0u32;
+        for trader in traders.iter() {
+            if Self::tally_account(&env, &mut state, &trader)? {
+                tallied += 1;
+            }
+        }
+
+        if !state.tallied && Self::shutdown_settled(&env) {
+            // LPs are left with the book less what traders are owed: losses
+            // beyond what a trader held were never collected
+            state.tallied = true;
+            state.vault_nav = (state.book - state.trader_claims).max(0);
+            state.total_claims = state.trader_claims + state.vault_nav;
+            let event = ShutdownTallyEvent {
+                version: EVENT_VERSION,
+                trader_claims: state.trader_claims,
+                vault_nav: state.vault_nav,
+                total_claims: state.total_claims,
+                assets: state.assets,
+            };
+            env.events().publish((symbol_short!("SD_TALLY"),), event);
+        }
+        env.storage().instance().set(&DataKey::Shutdown, &state);
+        Ok(tallied)
+    }
+
+    /// Pays out the trader's tallied claim, pro rata if the contract is short
+    /// of funds. Returns the amount transferred.
+    pub fn claim_shutdown(env: Env, trader: Address) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        trader.require_auth();
+
+        let mut state = Self::get_shutdown_state(&env)?;
+        if !state.tallied {
+            return Err(Error::TallyPending);
+        }
+        let key = DataKey::ShutdownClaim(trader.clone());
+        let claim: i128 = env.storage().persistent().get(&key).ok_or(Error::InvalidAmount)?;
+        env.storage().persistent().remove(&key);
+
+        let amount = Self::shutdown_payout(&state, claim)?;
+        state.paid += amount;
+        env.storage().instance().set(&DataKey::Shutdown, &state);
+
+        #[cfg(not(test))]
+        if amount > 0 {
+            let token_addr: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+            let token = Token::new(&env, &token_addr);
+            token.transfer(&env.current_contract_address(), &trader, &amount);
+        }
+
+        let event = ShutdownClaimEvent { version: EVENT_VERSION, claimant: trader.clone(), claim, amount };
+        env.events().publish((symbol_short!("SD_CLAIM"), trader), event);
+        Ok(amount)
+    }
+
+    /// Pays out the LP's share of the tallied vault NAV, pending withdrawal
+    /// requests included. Returns the amount transferred.
+    pub fn lp_claim_shutdown(env: Env, lp: Address) -> Result<i128, Error> {
+        Self::bump_instance(&env);
+        lp.require_auth();
+
+        let mut state = Self::get_shutdown_state(&env)?;
+        if !state.tallied {
+            return Err(Error::TallyPending);
+        }
+        let position = Self::get_lp(&env, &lp);
+        if position.shares == 0 {
+            return Err(Error::InvalidAmount);
+        }
+        let claim = (position.shares * state.vault_nav) / state.total_shares;
+        Self::put_lp(&env, &lp, &LpPosition { shares: 0, last_deposit: 0, pending_shares: 0, requested_at: 0 });
+
+        let amount = Self::shutdown_payout(&state, claim)?;
+        state.paid += amount;
+        env.storage().instance().set(&DataKey::Shutdown, &state);
+
+        #[cfg(not(test))]
+        if amount > 0 {
+            let token_addr: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+            let token = Token::new(&env, &token_addr);
+            token.transfer(&env.current_contract_address(), &lp, &amount);
+        }
+
+        let event = ShutdownClaimEvent { version: EVENT_VERSION, claimant: lp.clone(), claim, amount };
+        env.events().publish((symbol_short!("SD_LP"), lp), event);
+        Ok(amount)
+    }
+
+    pub fn set_operation_paused(env: Env, caller: Address, op: Operation, paused: bool) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        let role = if paused { Role::Guardian } else { Role::Owner };
+        Self::require_role(&env, &caller, role)?;
+        if paused {
+            env.storage().instance().set(&DataKey::OperationPaused(op), &true);
+        } else {
+            env.storage().instance().remove(&DataKey::OperationPaused(op));
+        }
+        env.events().publish((symbol_short!("OP_PAUSE"), op), PauseEvent { version: EVENT_VERSION, caller, operation: op, paused });
+        Ok(())
+    }
+
+    pub fn get_global_status_view(env: Env) -> MarketStatus {
+        Self::bump_instance(&env);
+        Self::get_global_status(&env)
+    }
+
+    // Effective status of a market, global status included
+    pub fn get_market_status(env: Env, symbol: Symbol) -> Result<MarketStatus, Error> {
+        Self::bump_instance(&env);
+        Self::validate_symbol(&symbol)?;
+        Ok(Self::effective_status(&env, &symbol))
+    }
+
+    pub fn is_operation_paused(env: Env, op: Operation) -> bool {
+        Self::bump_instance(&env);
+        env.storage().instance().get(&DataKey::OperationPaused(op)).unwrap_or(false)
+    }
+
+    // ---------------- Upgrades ----------------
+    /// Swap the contract code. Entries in an older layout stay readable, but
+    /// state-changing entrypoints refuse to run until `migrate` has rewritten
+    /// them and `complete_migration` has recorded the new version.
+    pub fn upgrade(env: Env, caller: Address, new_wasm_hash: BytesN<32>) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+        env.deployer().update_current_contract_wasm(new_wasm_hash.clone());
+        env.events().publish((symbol_short!("UPGRADE"),), UpgradeEvent { version: EVENT_VERSION, caller, wasm_hash: new_wasm_hash });
+        Ok(())
+    }
+
+    /// Folds each market's legacy per-field entries into one `Market` entry,
+    /// then each listed trader's collateral and positions into an `Account`
+    /// entry, rewriting v1 positions in the current layout. Schema 1 stored
+    /// neither net notional, gross open interest nor total collateral, so
+    /// they are rebuilt from the records folded here. Entries already in the
+    /// current layout are left alone, so batches can be retried or overlap.
+    /// Returns the number of entries rewritten.
+    pub fn migrate(env: Env, caller: Address, traders: Vec<Address>) -> Result<u32, Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+
+        let from_v1 = Self::schema_version(&env) == 1;
+        let mut migrated = 0u32;
+        if let Some(paused) = env.storage().instance().get::<DataKey, bool>(&DataKey::Paused) {
+            if paused {
+                env.storage().instance().set(&DataKey::GlobalStatus, &MarketStatus::Halted);
+            }
+            env.storage().instance().remove(&DataKey::Paused);
+            migrated += 1;
+        }
+        for symbol in Self::supported_symbols(&env) {
+            let legacy_keys = Self::legacy_market_keys(&symbol);
+            if !legacy_keys.iter().any(|key| env.storage().persistent().has(key)) {
+                continue;
+            }
+            if !env.storage().persistent().has(&DataKey::Market(symbol.clone())) {
+                Self::put_market_state(&env, &symbol, &Self::legacy_market_state(&env, &symbol));
+            }
+            for key in legacy_keys.iter() {
+                env.storage().persistent().remove(key);
+            }
+            migrated += 1;
+        }
+        for trader in traders.iter() {
+            let legacy_key = DataKey::Collateral(trader.clone());
+            let Some(collateral) = env.storage().persistent().get::<DataKey, i128>(&legacy_key) else {
+                continue;
+            };
+            for symbol in Self::supported_symbols(&env) {
+                let key = DataKey::Position(trader.clone(), symbol.clone());
+                let Some(raw) = env.storage().persistent().get::<DataKey, Val>(&key) else {
+                    continue;
+                };
+                if Self::has_field(&env, &raw, "last_update") {
+                    continue;
+                }
+                let position = Self::decode_position(&env, &raw)?;
+                env.storage().persistent().set(&key, &position);
+                Self::extend_persistent(&env, &key);
+                let mut market = Self::market_state(&env, &symbol);
+                market.net_notional += Self::signed_notional(position.size, position.notional);
+                market.open_interest = Self::open_interest_after(&market.open_interest, 0, position.size);
+                Self::put_market_state(&env, &symbol, &market);
+                migrated += 1;
+            }
+            if !env.storage().persistent().has(&DataKey::Account(trader.clone())) {
+                Self::put_account(&env, &trader, &Self::legacy_account(&env, &trader));
+            }
+            if from_v1 {
+                let total: i128 = env.storage().instance().get(&DataKey::TotalCollateral).unwrap_or(0);
+                env.storage().instance().set(&DataKey::TotalCollateral, &(total + collateral));
+            }
+            env.storage().persistent().remove(&legacy_key);
+            migrated += 1;
+        }
+
+        let event = MigrationEvent { version: EVENT_VERSION, caller, schema_version: SCHEMA_VERSION, migrated };
+        env.events().publish((symbol_short!("MIGRATE"), SCHEMA_VERSION), event);
+        Ok(migrated)
+    }
+
+    /// Records SCHEMA_VERSION, which re-enables trading, once every market is
+    /// folded and each market's rebuilt long and short open interest nets to
+    /// its net OI. The latter fails while a trader with an open position is
+    /// still unmigrated.
+    pub fn complete_migration(env: Env, caller: Address) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Owner)?;
+
+        for symbol in Self::supported_symbols(&env) {
+            if Self::legacy_market_keys(&symbol).iter().any(|key| env.storage().persistent().has(key)) {
+                return Err(Error::MigrationPending);
+            }
+            let market = Self::market_state(&env, &symbol);
+            if market.open_interest.long - market.open_interest.short != market.net_oi {
+                return Err(Error::MigrationPending);
+            }
+        }
+
+        env.storage().instance().set(&DataKey::SchemaVersion, &SCHEMA_VERSION);
+        let event = MigrationEvent { version: EVENT_VERSION, caller, schema_version: SCHEMA_VERSION, migrated: 0 };
+        env.events().publish((symbol_short!("MIGRATED"), SCHEMA_VERSION), event);
+        Ok(())
+    }
+
+    pub fn get_schema_version(env: Env) -> u32 {
+        Self::bump_instance(&env);
+        Self::schema_version(&env)
+    }
+
+    // ---------------- Storage rent ----------------
+    /// Extend the TTL of every entry belonging to `trader`: collateral,
+    /// margin mode, LP position and each open position. Anyone may call it.
+    pub fn bump_account(env: Env, trader: Address) {
+        Self::bump_instance(&env);
+        Self::extend_account(&env, &trader);
+        // Ledgers outlive positions, so cover markets the trader has left too
+        for symbol in Self::supported_symbols(&env) {
+            Self::extend_if_present(&env, &DataKey::Ledger(trader.clone(), symbol));
+        }
+    }
+
+    /// Extend the TTL of every stored entry of one market. Anyone may call it.
+    pub fn bump_market(env: Env, symbol: Symbol) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::validate_symbol(&symbol)?;
+        let keys = [
+            DataKey::Market(symbol.clone()),
+            DataKey::MarketLimits(symbol.clone()),
+            DataKey::MarginTiers(symbol.clone()),
+            DataKey::MarketStatus(symbol.clone()),
+            DataKey::Settlement(symbol.clone()),
+        ];
+        // Entries not yet folded into `Market` stay alive until `migrate` runs
+        for key in keys.iter().chain(Self::legacy_market_keys(&symbol).iter()) {
+            Self::extend_if_present(&env, key);
+        }
+        Ok(())
+    }
+
+    // ---------------- Timelocked parameters ----------------
+    /// Queue a parameter change; it can be executed by anyone once the
+    /// timelock delay has passed, and cancelled by a guardian until then.
+    /// Returns the change id.
+    pub fn queue_change(env: Env, caller: Address, change: ParamChange) -> Result<u64, Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Self::change_role(&change))?;
+        Self::validate_change(&change)?;
+
+        let id: u64 = env.storage().instance().get(&DataKey::NextChangeId).unwrap_or(0);
+        env.storage().instance().set(&DataKey::NextChangeId, &(id + 1));
+
+        let queued = QueuedChange {
+            id,
+            change,
+            proposer: caller,
+            eta: env.ledger().timestamp() + Self::timelock_delay(&env),
+        };
+        let mut queue = Self::queued_changes(&env);
+        queue.push_back(queued.clone());
+        Self::put_queued_changes(&env, &queue);
+
+        let event = QueueEvent { version: EVENT_VERSION, id, caller: queued.proposer, change: queued.change, eta: queued.eta };
+        env.events().publish((symbol_short!("QUEUED"), id), event);
+        Ok(id)
+    }
+
+    pub fn execute_change(env: Env, id: u64) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        let mut queue = Self::queued_changes(&env);
+        let index = Self::queued_index(&queue, id)?;
+        let queued = queue.get_unchecked(index);
+        if env.ledger().timestamp() < queued.eta {
+            return Err(Error::TimelockActive);
+        }
+        queue.remove(index);
+        Self::put_queued_changes(&env, &queue);
+
+        Self::apply_change(&env, queued.change.clone());
+        let event = ConfigEvent { version: EVENT_VERSION, id, proposer: queued.proposer, change: queued.change };
+        env.events().publish((symbol_short!("CONFIG"), id), event);
+        Ok(())
+    }
+
+    pub fn cancel_change(env: Env, caller: Address, id: u64) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::Guardian)?;
+        let mut queue = Self::queued_changes(&env);
+        let index = Self::queued_index(&queue, id)?;
+        let queued = queue.get_unchecked(index);
+        queue.remove(index);
+        Self::put_queued_changes(&env, &queue);
+        let event = QueueEvent { version: EVENT_VERSION, id, caller, change: queued.change, eta: queued.eta };
+        env.events().publish((symbol_short!("CANCELLED"), id), event);
+        Ok(())
+    }
+
+    pub fn get_queued_changes(env: Env) -> Vec<QueuedChange> {
+        Self::bump_instance(&env);
+        Self::queued_changes(&env)
+    }
+
+    pub fn get_timelock_delay(env: Env) -> u64 {
+        Self::bump_instance(&env);
+        Self::timelock_delay(&env)
+    }
+
+    pub fn get_fee_bp(env: Env) -> i128 {
+        Self::bump_instance(&env);
+        Self::fee_bp(&env)
+    }
+
+    pub fn get_protocol_fee_bp(env: Env) -> i128 {
+        Self::bump_instance(&env);
+        Self::protocol_fee_bp(&env)
+    }
+
+    pub fn get_treasury(env: Env) -> Map<Address, i128> {
+        Self::bump_instance(&env);
+        Self::treasury(&env)
+    }
+
+    pub fn get_oracle(env: Env) -> Address {
+        Self::bump_instance(&env);
+        oracle_address(&env)
+    }
+
+    pub fn get_liquidation_auction(env: Env) -> LiquidationAuction {
+        Self::bump_instance(&env);
+        Self::get_auction(&env)
+    }
+
+    pub fn get_margin_tiers(env: Env, symbol: Symbol) -> Result<Vec<MarginTier>, Error> {
+        Self::bump_instance(&env);
+        Self::validate_symbol(&symbol)?;
+        Ok(Self::margin_tiers(&env, &symbol))
+    }
+
+    // Pass `None` to turn backstop mode off and close liquidations on the AMM again
+    pub fn set_backstop(env: Env, caller: Address, backstop: Option<Address>) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::require_role(&env, &caller, Role::RiskManager)?;
+        match &backstop {
+            Some(addr) => env.storage().instance().set(&DataKey::Backstop, addr),
+            None => env.storage().instance().remove(&DataKey::Backstop),
+        }
+        env.events().publish((symbol_short!("BACKSTOP"),), BackstopEvent { version: EVENT_VERSION, caller, backstop });
+        Ok(())
+    }
+
+    pub fn get_backstop(env: Env) -> Option<Address> {
+        Self::bump_instance(&env);
+        env.storage().instance().get(&DataKey::Backstop)
+    }
+
+    // Internal helper functions
+    fn get_admin(env: &Env) -> Result<Address, Error> {
+        env.storage().instance()
+            .get(&DataKey::Admin)
+            .ok_or(Error::NotInitialized)
+    }
+
+    fn get_shutdown_state(env: &Env) -> Result<ShutdownState, Error> {
+        env.storage().instance()
+            .get(&DataKey::Shutdown)
+            .ok_or(Error::NotShutDown)
+    }
+
+    // Collateral plus every position's PnL net of funding at the shutdown
+    // prices. Isolated losses stop at the position margin; equity floors at 0.
+    fn shutdown_equity(env: &Env, state: &ShutdownState, trader: &Address) -> i128 {
+        Self::shutdown_balance(env, state, trader).max(0)
+    }
+
+    // USD equity at the shutdown prices, negative when the account owes a shortfall
+    fn shutdown_balance(env: &Env, state: &ShutdownState, trader: &Address) -> i128 {
+        let mode = Self::get_margin_mode(env, trader);
+        let account = Self::account(env, trader);
+        let mut equity = account.collateral;
+        for symbol in account.markets {
+            let price = state.prices.get(symbol.clone()).unwrap_or(0);
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            if let Some(position) = Self::load_position(env, &position_key) {
+                let funding = Self::get_funding_data(env, &symbol);
+                let mut delta = Self::calculate_unrealized_pnl(&position, price)
+                    - Self::calculate_funding_payment(&position, &funding);
+                if mode == MarginMode::Isolated {
+                    delta = delta.max(-position.margin);
+                }
+                equity += delta;
+            }
+        }
+        equity
+    }
+
+    // Closes the account at the shutdown prices and records its claim.
+    // Returns false for an account already tallied or holding nothing.
+    fn tally_account(env: &Env, state: &mut ShutdownState, trader: &Address) -> Result<bool, Error> {
+        let claim_key = DataKey::ShutdownClaim(trader.clone());
+        let account = Self::account(env, trader);
+        let tokens = Self::token_collateral(env, trader);
+        if env.storage().persistent().has(&claim_key)
+            || (account.collateral == 0 && account.markets.is_empty() && tokens.is_empty())
+        {
+            return Ok(false);
+        }
+
+        let balance = Self::shutdown_balance(env, state, trader);
+        let mode = Self::get_margin_mode(env, trader);
+        for symbol in account.markets {
+            if let Some(position) = Self::load_position(env, &DataKey::Position(trader.clone(), symbol.clone())) {
+                let price = state.prices.get(symbol.clone()).unwrap_or(0);
+                let pnl = Self::calculate_unrealized_pnl(&position, price);
+                let funding = Self::calculate_funding_payment(&position, &Self::get_funding_data(env, &symbol));
+                // Isolated losses beyond the margin were never charged, as in `shutdown_balance`
+                let mut realized_pnl = pnl;
+                if mode == MarginMode::Isolated {
+                    realized_pnl += (pnl - funding).max(-position.margin) - (pnl - funding);
+                }
+                let volume = (position.size.abs() * price) / DEC_P;
+                Self::record(env, trader, &symbol, Self::ledger_entry(realized_pnl, 0, funding, 0, volume));
+
+                let mut market = Self::market_state(env, &symbol);
+                market.net_oi -= position.size;
+                market.net_notional -= Self::signed_notional(position.size, position.notional);
+                market.open_interest = Self::open_interest_after(&market.open_interest, position.size, 0);
+                Self::put_market_state(env, &symbol, &market);
+            }
+            Self::put_position(env, trader, &symbol, None);
+        }
+        Self::put_collateral(env, trader, 0);
+
+        // Token collateral is returned in kind once it has covered any USD
+        // shortfall at its haircut value. The vault NAV never counts that
+        // shortfall, so LPs have already absorbed the loss and the tokens
+        // that cover it go to the treasury.
+        let mut shortfall = -balance;
+        for (token, amount) in tokens {
+            let seized = if shortfall > 0 {
+                let price = state.collateral_prices.get(token.clone()).unwrap_or(0);
+                let asset = Self::collateral_asset(env, &token).ok_or(Error::AssetNotListed)?;
+                let value = Self::haircut_value(&asset, amount, price)?;
+                if value <= shortfall {
+                    shortfall -= value;
+                    amount
+                } else {
+                    // Round up so the shortfall is fully covered
+                    let per_unit = price * (10_000 - asset.haircut_bp);
+                    let scaled = shortfall.checked_mul(10i128.pow(asset.decimals) * 10_000).ok_or(Error::Overflow)?;
+                    shortfall = 0;
+                    ((scaled + per_unit - 1) / per_unit).min(amount)
+                }
+            } else {
+                0
+            };
+            if seized > 0 {
+                Self::credit_treasury(env, &token, seized);
+            }
+
+            let deposits_key = DataKey::CollateralDeposits(token.clone());
+            let deposits: i128 = env.storage().instance().get(&deposits_key).unwrap_or(0);
+            env.storage().instance().set(&deposits_key, &(deposits - amount));
+            Self::put_token_balance(env, trader, &token, 0);
+
+            #[cfg(not(test))]
+            if amount > seized {
+                let collateral_token = Token::new(env, &token);
+                collateral_token.transfer(&env.current_contract_address(), trader, &(amount - seized));
+            }
+        }
+
+        let claim = balance.max(0);
+        env.storage().persistent().set(&claim_key, &claim);
+        Self::extend_persistent(env, &claim_key);
+        state.trader_claims += claim;
+        Ok(true)
+    }
+
+    // Every account is tallied once no collateral, token deposit or open interest is left
+    fn shutdown_settled(env: &Env) -> bool {
+        let total_collateral: i128 = env.storage().instance().get(&DataKey::TotalCollateral).unwrap_or(0);
+        total_collateral == 0
+            && Self::collateral_assets(env).iter().all(|token| {
+                env.storage().instance().get::<DataKey, i128>(&DataKey::CollateralDeposits(token)).unwrap_or(0) == 0
+            })
+            && Self::supported_symbols(env).iter().all(|symbol| {
+                let open_interest = Self::market_state(env, &symbol).open_interest;
+                open_interest.long == 0 && open_interest.short == 0
+            })
+    }
+
+    // Oracle price for the shutdown snapshot, or the caller's fallback when the feed is down
+    fn shutdown_oracle_price(
+        env: &Env,
+        symbol: &Symbol,
+        fallback_prices: &Map<Symbol, i128>,
+        oracle_prices: &mut Map<Symbol, i128>,
+    ) -> Result<i128, Error> {
+        let price = match Self::cached_oracle_price(env, symbol, oracle_prices) {
+            Ok(price) => price,
+            Err(e) => fallback_prices.get(symbol.clone()).filter(|p| *p > 0).ok_or(e)?,
+        };
+        oracle_prices.set(symbol.clone(), price);
+        Ok(price)
+    }
+
+    // Claim scaled by `assets / total_claims` when the contract is insolvent.
+    // Every claim is scaled alike and rounded down, so payouts never add up
+    // to more than the assets, whatever order they are claimed in.
+    fn shutdown_payout(state: &ShutdownState, claim: i128) -> Result<i128, Error> {
+        if state.assets >= state.total_claims {
+            return Ok(claim);
+        }
+        Ok(claim.checked_mul(state.assets.max(0)).ok_or(Error::Overflow)? / state.total_claims)
+    }
+
+    fn get_settlement(env: &Env, symbol: &Symbol) -> Option<MarketSettlement> {
+        env.storage().persistent().get(&DataKey::Settlement(symbol.clone()))
+    }
+
+    // Authenticates `caller` and checks it holds `role` (the owner holds all)
+    fn require_role(env: &Env, caller: &Address, role: Role) -> Result<(), Error> {
+        caller.require_auth();
+        // Surface NotInitialized before any membership lookup
+        Self::get_admin(env)?;
+        if Self::check_role(env, role, caller) {
+            Ok(())
+        } else {
+            Err(Error::Unauthorized)
+        }
+    }
+
+    fn check_role(env: &Env, role: Role, account: &Address) -> bool {
+        match Self::get_admin(env) {
+            Ok(admin) if admin == *account => true,
+            _ => role != Role::Owner && Self::role_members(env, role).contains(account),
+        }
+    }
+
+    fn role_members(env: &Env, role: Role) -> Vec<Address> {
+        env.storage().persistent()
+            .get(&DataKey::RoleMembers(role))
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn put_role_members(env: &Env, role: Role, members: &Vec<Address>) {
+        let key = DataKey::RoleMembers(role);
+        env.storage().persistent().set(&key, members);
+        Self::extend_persistent(env, &key);
+    }
+
+    fn status_change_role(current: MarketStatus, new: MarketStatus) -> Role {
+        if (new as u32) < (current as u32) { Role::Owner } else { Role::Guardian }
+    }
+
+    // Who may queue each kind of change
+    fn change_role(change: &ParamChange) -> Role {
+        match change {
+            ParamChange::Oracle(_) => Role::OracleManager,
+            ParamChange::CollateralToken(_) | ParamChange::TimelockDelay(_) | ParamChange::ProtocolFeeBp(_) => Role::Owner,
+            _ => Role::RiskManager,
+        }
+    }
+
+    fn validate_change(change: &ParamChange) -> Result<(), Error> {
+        match change {
+            // The first tier must start at 0, thresholds must be strictly
+            // increasing, and IMR/MMR may not decrease from one tier to the
+            // next, with 0 < MMR < IMR ≤ 100% in every tier
+            ParamChange::MarginTiers(symbol, tiers) => {
+                Self::validate_symbol(symbol)?;
+                if tiers.is_empty() || tiers.get_unchecked(0).min_notional != 0 {
+                    return Err(Error::InvalidAmount);
+                }
+                let mut previous: Option<MarginTier> = None;
+                for tier in tiers.iter() {
+                    if tier.mmr_bp <= 0 || tier.mmr_bp >= tier.imr_bp || tier.imr_bp > 10_000 {
+                        return Err(Error::InvalidAmount);
+                    }
+                    if let Some(prev) = &previous {
+                        if tier.min_notional <= prev.min_notional
+                            || tier.imr_bp < prev.imr_bp
+                            || tier.mmr_bp < prev.mmr_bp
+                        {
+                            return Err(Error::InvalidAmount);
+                        }
+                    }
+                    previous = Some(tier);
+                }
+            }
+            ParamChange::MarketLimits(symbol, limits) => {
+                Self::validate_symbol(symbol)?;
+                if limits.max_long_oi <= 0 || limits.max_short_oi <= 0 || limits.max_position_size <= 0 {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::LiquidationAuction(auction) => {
+                if auction.min_bonus_bp < 0
+                    || auction.min_bonus_bp > auction.max_bonus_bp
+                    || auction.max_bonus_bp > 10_000
+                {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::VaultConfig(config) => {
+                if config.max_utilization_bp <= 0 {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::FeeBp(fee_bp) => {
+                if !(0..=MAX_FEE_BP).contains(fee_bp) {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::ProtocolFeeBp(share_bp) => {
+                if !(0..=10_000).contains(share_bp) {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::TimelockDelay(delay) => {
+                if *delay > MAX_TIMELOCK_DELAY {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::CollateralAsset(_, asset) => {
+                if !(0..=10_000).contains(&asset.haircut_bp) || asset.deposit_cap < 0 || asset.decimals > 18 {
+                    return Err(Error::InvalidAmount);
+                }
+            }
+            ParamChange::Oracle(_) | ParamChange::CollateralToken(_) => {}
+        }
+        Ok(())
+    }
+
+    fn apply_change(env: &Env, change: ParamChange) {
+        match change {
+            ParamChange::MarginTiers(symbol, tiers) => {
+                let key = DataKey::MarginTiers(symbol.clone());
+                env.storage().persistent().set(&key, &tiers);
+                Self::extend_persistent(env, &key);
+            }
+            ParamChange::MarketLimits(symbol, limits) => {
+                let key = DataKey::MarketLimits(symbol.clone());
+                env.storage().persistent().set(&key, &limits);
+                Self::extend_persistent(env, &key);
+            }
+            ParamChange::LiquidationAuction(auction) => {
+                env.storage().instance().set(&DataKey::LiquidationAuction, &auction);
+            }
+            ParamChange::VaultConfig(config) => {
+                env.storage().instance().set(&DataKey::VaultConfig, &config);
+            }
+            ParamChange::FeeBp(fee_bp) => {
+                env.storage().instance().set(&DataKey::FeeBp, &fee_bp);
+            }
+            ParamChange::ProtocolFeeBp(share_bp) => {
+                env.storage().instance().set(&DataKey::ProtocolFeeBp, &share_bp);
+            }
+            ParamChange::Oracle(oracle) => {
+                env.storage().instance().set(&DataKey::Oracle, &oracle);
+            }
+            ParamChange::CollateralToken(token_addr) => {
+                env.storage().instance().set(&DataKey::CollateralToken, &token_addr);
+            }
+            ParamChange::CollateralAsset(token, asset) => {
+                let key = DataKey::CollateralAsset(token.clone());
+                env.storage().persistent().set(&key, &asset);
+                Self::extend_persistent(env, &key);
+                let mut listed = Self::collateral_assets(env);
+                if !listed.contains(&token) {
+                    listed.push_back(token);
+                    env.storage().instance().set(&DataKey::CollateralAssets, &listed);
+                }
+            }
+            ParamChange::TimelockDelay(delay) => {
+                env.storage().instance().set(&DataKey::TimelockDelay, &delay);
+            }
+        }
+    }
+
+    fn timelock_delay(env: &Env) -> u64 {
+        env.storage().instance()
+            .get(&DataKey::TimelockDelay)
+            .unwrap_or(DEFAULT_TIMELOCK_DELAY)
+    }
+
+    fn fee_bp(env: &Env) -> i128 {
+        env.storage().instance()
+            .get(&DataKey::FeeBp)
+            .unwrap_or(FEE_BP)
+    }
+
+    fn protocol_fee_bp(env: &Env) -> i128 {
+        env.storage().instance()
+            .get(&DataKey::ProtocolFeeBp)
+            .unwrap_or(PROTOCOL_FEE_BP)
+    }
+
+    fn treasury(env: &Env) -> Map<Address, i128> {
+        env.storage().persistent()
+            .get(&DataKey::Treasury)
+            .unwrap_or(Map::new(env))
+    }
+
+    fn credit_treasury(env: &Env, token: &Address, amount: i128) {
+        let mut treasury = Self::treasury(env);
+        treasury.set(token.clone(), treasury.get(token.clone()).unwrap_or(0) + amount);
+        env.storage().persistent().set(&DataKey::Treasury, &treasury);
+        Self::extend_persistent(env, &DataKey::Treasury);
+    }
+
+    fn queued_changes(env: &Env) -> Vec<QueuedChange> {
+        env.storage().persistent()
+            .get(&DataKey::QueuedChanges)
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn put_queued_changes(env: &Env, queue: &Vec<QueuedChange>) {
+        env.storage().persistent().set(&DataKey::QueuedChanges, queue);
+        Self::extend_persistent(env, &DataKey::QueuedChanges);
+    }
+
+    fn queued_index(queue: &Vec<QueuedChange>, id: u64) -> Result<u32, Error> {
+        queue.iter()
+            .position(|queued| queued.id == id)
+            .map(|index| index as u32)
+            .ok_or(Error::ChangeNotFound)
+    }
+
+    fn schema_version(env: &Env) -> u32 {
+        env.storage().instance().get(&DataKey::SchemaVersion).unwrap_or(1)
+    }
+
+    fn get_global_status(env: &Env) -> MarketStatus {
+        env.storage().instance()
+            .get(&DataKey::GlobalStatus)
+            .unwrap_or(MarketStatus::Active)
+    }
+
+    // Stricter of the market's own status and the global one
+    fn effective_status(env: &Env, symbol: &Symbol) -> MarketStatus {
+        if Self::get_settlement(env, symbol).is_some() {
+            return MarketStatus::Halted;
+        }
+        let global = Self::get_global_status(env);
+        let market = env.storage().persistent()
+            .get(&DataKey::MarketStatus(symbol.clone()))
+            .unwrap_or(MarketStatus::Active);
+        if (market as u32) > (global as u32) { market } else { global }
+    }
+
+    fn status_allows(status: MarketStatus, op: Operation) -> Result<(), Error> {
+        match status {
+            MarketStatus::Active => Ok(()),
+            MarketStatus::ReduceOnly => match op {
+                Operation::Open | Operation::RemoveMargin => Err(Error::ReduceOnly),
+                _ => Ok(()),
+            },
+            MarketStatus::Halted => Err(Error::Paused),
+        }
+    }
+
+    // Account- and vault-level operations: the operation flag and the global status
+    fn check_operation(env: &Env, op: Operation) -> Result<(), Error> {
+        if env.storage().instance().has(&DataKey::Shutdown) {
+            return Err(Error::ShutDown);
+        }
+        // Writes before every legacy entry is rewritten would be built on
+        // the stale aggregates `migrate` is about to rebuild
+        if Self::schema_version(env) < SCHEMA_VERSION {
+            return Err(Error::MigrationPending);
+        }
+        if env.storage().instance().get(&DataKey::OperationPaused(op)).unwrap_or(false) {
+            return Err(Error::Paused);
+        }
+        Self::status_allows(Self::get_global_status(env), op)
+    }
+
+    // Market operations: as above, plus the market's own status
+    fn check_market(env: &Env, symbol: &Symbol, op: Operation) -> Result<(), Error> {
+        if Self::get_settlement(env, symbol).is_some() {
+            return Err(Error::MarketSettled);
+        }
+        Self::check_operation(env, op)?;
+        Self::status_allows(Self::effective_status(env, symbol), op)
+    }
+
+    fn supported_symbols(env: &Env) -> Vec<Symbol> {
+        vec![env, symbol_short!("XLM"), symbol_short!("BTC"), symbol_short!("ETH")]
+    }
+
+    fn validate_symbol(symbol: &Symbol) -> Result<(), Error> {
+        let valid_symbols = [
+            symbol_short!("XLM"),
+            symbol_short!("BTC"),
+            symbol_short!("ETH"),
+        ];
+        
+        if valid_symbols.contains(symbol) {
+            Ok(())
+        } else {
+            Err(Error::InvalidSymbol)
+        }
+    }
+
+    fn bump_instance(env: &Env) {
+        env.storage().instance().extend_ttl(INSTANCE_BUMP_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    }
+
+    // (threshold, extend_to) for a persistent key
+    fn ttl_policy(key: &DataKey) -> (u32, u32) {
+        match key {
+            DataKey::Account(_)
+            | DataKey::Collateral(_)
+            | DataKey::Position(_, _)
+            | DataKey::MarginMode(_)
+            | DataKey::LiquidationStart(_, _)
+            | DataKey::Ledger(_, _)
+            | DataKey::TokenCollateral(_)
+            | DataKey::ShutdownClaim(_)
+            | DataKey::LpPosition(_) => (ACCOUNT_BUMP_THRESHOLD, ACCOUNT_BUMP_AMOUNT),
+            DataKey::Market(_)
+            | DataKey::Reserves(_)
+            | DataKey::Funding(_)
+            | DataKey::SkewScale(_)
+            | DataKey::NetOi(_)
+            | DataKey::MarketLimits(_)
+            | DataKey::MarginTiers(_)
+            | DataKey::MarketStatus(_)
+            | DataKey::Settlement(_) => (MARKET_BUMP_THRESHOLD, MARKET_BUMP_AMOUNT),
+            _ => (PROTOCOL_BUMP_THRESHOLD, PROTOCOL_BUMP_AMOUNT),
+        }
+    }
+
+    fn extend_persistent(env: &Env, key: &DataKey) {
+        let (threshold, extend_to) = Self::ttl_policy(key);
+        env.storage().persistent().extend_ttl(key, threshold, extend_to);
+    }
+
+    fn extend_if_present(env: &Env, key: &DataKey) {
+        if env.storage().persistent().has(key) {
+            Self::extend_persistent(env, key);
+        }
+    }
+
+    // Positions are only reachable through the account, so they live and die with it
+    fn extend_account(env: &Env, trader: &Address) {
+        Self::extend_if_present(env, &DataKey::Account(trader.clone()));
+        Self::extend_if_present(env, &DataKey::Collateral(trader.clone()));
+        Self::extend_if_present(env, &DataKey::MarginMode(trader.clone()));
+        Self::extend_if_present(env, &DataKey::LpPosition(trader.clone()));
+        Self::extend_if_present(env, &DataKey::TokenCollateral(trader.clone()));
+        for symbol in Self::account(env, trader).markets {
+            Self::extend_if_present(env, &DataKey::Position(trader.clone(), symbol.clone()));
+            Self::extend_if_present(env, &DataKey::LiquidationStart(trader.clone(), symbol));
+        }
+    }
+
+    fn account(env: &Env, trader: &Address) -> Account {
+        env.storage().persistent()
+            .get(&DataKey::Account(trader.clone()))
+            .unwrap_or_else(|| Self::legacy_account(env, trader))
+    }
+
+    fn put_account(env: &Env, trader: &Address, account: &Account) {
+        env.storage().persistent().set(&DataKey::Account(trader.clone()), account);
+        Self::extend_account(env, trader);
+    }
+
+    // Schema v1 only stored collateral; the rest comes from a scan.
+    // Every account that held a position then also had a collateral entry.
+    fn legacy_account(env: &Env, trader: &Address) -> Account {
+        let mut account = Account { collateral: 0, margin_used: 0, markets: Vec::new(env) };
+        let Some(collateral) = env.storage().persistent().get(&DataKey::Collateral(trader.clone())) else {
+            return account;
+        };
+        account.collateral = collateral;
+        for symbol in Self::supported_symbols(env) {
+            if let Some(position) = Self::load_position(env, &DataKey::Position(trader.clone(), symbol.clone())) {
+                account.margin_used += position.margin;
+                account.markets.push_back(symbol);
+            }
+        }
+        account
+    }
+
+    fn trade_ledger(env: &Env, trader: &Address, symbol: &Symbol) -> TradeLedger {
+        env.storage().persistent()
+            .get(&DataKey::Ledger(trader.clone(), symbol.clone()))
+            .unwrap_or_default()
+    }
+
+    // One realization; `funding` is signed like `calculate_funding_payment`
+    fn ledger_entry(pnl: i128, fee: i128, funding: i128, liquidation_loss: i128, volume: i128) -> TradeLedger {
+        TradeLedger {
+            realized_pnl: pnl,
+            fees_paid: fee,
+            funding_paid: funding.max(0),
+            funding_received: (-funding).max(0),
+            liquidation_losses: liquidation_loss,
+            volume,
+        }
+    }
+
+    fn record(env: &Env, trader: &Address, symbol: &Symbol, entry: TradeLedger) {
+        let key = DataKey::Ledger(trader.clone(), symbol.clone());
+        let mut ledger = Self::trade_ledger(env, trader, symbol);
+        ledger.realized_pnl += entry.realized_pnl;
+        ledger.fees_paid += entry.fees_paid;
+        ledger.funding_paid += entry.funding_paid;
+        ledger.funding_received += entry.funding_received;
+        ledger.liquidation_losses += entry.liquidation_losses;
+        ledger.volume += entry.volume;
+        env.storage().persistent().set(&key, &ledger);
+        Self::extend_persistent(env, &key);
+    }
+
+    fn get_collateral(env: &Env, trader: &Address) -> i128 {
+        Self::account(env, trader).collateral
+    }
+
+    // Every collateral write goes through here so `TotalCollateral` stays in sync
+    fn put_collateral(env: &Env, trader: &Address, amount: i128) {
+        let mut account = Self::account(env, trader);
+        let previous = account.collateral;
+        account.collateral = amount;
+        Self::put_account(env, trader, &account);
+        let total: i128 = env.storage().instance().get(&DataKey::TotalCollateral).unwrap_or(0);
+        env.storage().instance().set(&DataKey::TotalCollateral, &(total + amount - previous));
+    }
+
+    fn collateral_assets(env: &Env) -> Vec<Address> {
+        env.storage().instance()
+            .get(&DataKey::CollateralAssets)
+            .unwrap_or(Vec::new(env))
+    }
+
+    fn collateral_asset(env: &Env, token: &Address) -> Option<CollateralAsset> {
+        env.storage().persistent().get(&DataKey::CollateralAsset(token.clone()))
+    }
+
+    fn token_collateral(env: &Env, trader: &Address) -> Map<Address, i128> {
+        env.storage().persistent()
+            .get(&DataKey::TokenCollateral(trader.clone()))
+            .unwrap_or(Map::new(env))
+    }
+
+    fn token_balance_of(env: &Env, trader: &Address, token: &Address) -> i128 {
+        Self::token_collateral(env, trader).get(token.clone()).unwrap_or(0)
+    }
+
+    // Zero balances are dropped, and so is the entry once it is empty
+    fn put_token_balance(env: &Env, trader: &Address, token: &Address, balance: i128) {
+        let key = DataKey::TokenCollateral(trader.clone());
+        let mut tokens = Self::token_collateral(env, trader);
+        if balance == 0 {
+            tokens.remove(token.clone());
+        } else {
+            tokens.set(token.clone(), balance);
+        }
+        if tokens.is_empty() {
+            env.storage().persistent().remove(&key);
+        } else {
+            env.storage().persistent().set(&key, &tokens);
+            Self::extend_persistent(env, &key);
+        }
+    }
+
+    // Token units → collateral units at the oracle price
+    fn token_value(asset: &CollateralAsset, amount: i128, price: i128) -> Result<i128, Error> {
+        Ok(amount.checked_mul(price).ok_or(Error::Overflow)? / 10i128.pow(asset.decimals))
+    }
+
+    fn haircut_value(asset: &CollateralAsset, amount: i128, price: i128) -> Result<i128, Error> {
+        Ok((Self::token_value(asset, amount, price)? * (10_000 - asset.haircut_bp)) / 10_000)
+    }
+
+    // Σ haircut value of the trader's non-USD collateral
+    fn token_collateral_value(env: &Env, trader: &Address, oracle_prices: &mut Map<Symbol, i128>) -> Result<i128, Error> {
+        let mut value = 0i128;
+        for (token, balance) in Self::token_collateral(env, trader) {
+            let asset = Self::collateral_asset(env, &token).ok_or(Error::AssetNotListed)?;
+            let price = Self::cached_oracle_price(env, &asset.asset, oracle_prices)?;
+            value += Self::haircut_value(&asset, balance, price)?;
+        }
+        Ok(value)
+    }
+
+    // Every position write goes through here so the account's margin and
+    // market list stay in sync. `None` removes the position.
+    fn put_position(env: &Env, trader: &Address, symbol: &Symbol, position: Option<&Position>) {
+        let key = DataKey::Position(trader.clone(), symbol.clone());
+        let previous_margin = Self::load_position(env, &key).map(|p| p.margin).unwrap_or(0);
+        let mut account = Self::account(env, trader);
+        account.margin_used += position.map(|p| p.margin).unwrap_or(0) - previous_margin;
+        match position {
+            Some(position) => {
+                env.storage().persistent().set(&key, position);
+                if !account.markets.contains(symbol) {
+                    account.markets.push_back(symbol.clone());
+                }
+            }
+            None => {
+                env.storage().persistent().remove(&key);
+                if let Some(index) = account.markets.first_index_of(symbol) {
+                    account.markets.remove(index);
+                }
+            }
+        }
+        Self::put_account(env, trader, &account);
+    }
+
+    fn market_state(env: &Env, symbol: &Symbol) -> MarketState {
+        env.storage().persistent()
+            .get(&DataKey::Market(symbol.clone()))
+            .unwrap_or_else(|| Self::legacy_market_state(env, symbol))
+    }
+
+    fn put_market_state(env: &Env, symbol: &Symbol, market: &MarketState) {
+        let key = DataKey::Market(symbol.clone());
+        env.storage().persistent().set(&key, market);
+        Self::extend_persistent(env, &key);
+    }
+
+    // Schema v1 kept each field under its own key and tracked neither net
+    // notional nor gross open interest; `migrate` rebuilds both from positions
+    fn legacy_market_state(env: &Env, symbol: &Symbol) -> MarketState {
+        let storage = env.storage().persistent();
+        MarketState {
+            reserve: storage.get(&DataKey::Reserves(symbol.clone())).unwrap_or(Reserve { base: 0, quote: 0 }),
+            funding: storage
+                .get::<DataKey, Val>(&DataKey::Funding(symbol.clone()))
+                .map(|raw| Self::decode_funding(env, &raw).unwrap_or_else(|e| panic_with_error!(env, e)))
+                .unwrap_or(FundingData { index: 0, last_update: 0 }),
+            net_oi: storage.get(&DataKey::NetOi(symbol.clone())).unwrap_or(0),
+            skew_scale: storage.get(&DataKey::SkewScale(symbol.clone())).unwrap_or(1),
+            net_notional: 0,
+            open_interest: OpenInterest { long: 0, short: 0 },
+        }
+    }
+
+    fn legacy_market_keys(symbol: &Symbol) -> [DataKey; 4] {
+        [
+            DataKey::Reserves(symbol.clone()),
+            DataKey::Funding(symbol.clone()),
+            DataKey::NetOi(symbol.clone()),
+            DataKey::SkewScale(symbol.clone()),
+        ]
+    }
+
+    // Only a store still being migrated can hold v1 positions
+    fn load_position(env: &Env, key: &DataKey) -> Option<Position> {
+        let storage = env.storage().persistent();
+        if Self::schema_version(env) == SCHEMA_VERSION {
+            return storage.get(key);
+        }
+        storage
+            .get::<DataKey, Val>(key)
+            .map(|raw| Self::decode_position(env, &raw).unwrap_or_else(|e| panic_with_error!(env, e)))
+    }
+
+    // Host-side struct decoding traps on a field mismatch, so pick the layout by its keys
+    fn has_field(env: &Env, raw: &Val, field: &str) -> bool {
+        Map::<Symbol, Val>::try_from_val(env, raw)
+            .map(|fields| fields.contains_key(Symbol::new(env, field)))
+            .unwrap_or(false)
+    }
+
+    // Current layout, or a schema v1 entry upgraded in memory. An entry in
+    // neither layout is one `migrate` cannot handle.
+    fn decode_position(env: &Env, raw: &Val) -> Result<Position, Error> {
+        if Self::has_field(env, raw, "last_update") {
+            return Position::try_from_val(env, raw).map_err(|_| Error::MigrationPending);
+        }
+        let v1 = PositionV1::try_from_val(env, raw).map_err(|_| Error::MigrationPending)?;
+        Ok(Position {
+            size: v1.size,
+            notional: v1.notional,
+            margin: v1.margin,
+            funding_index: v1.funding_index,
+            last_update: 0,
+        })
+    }
+
+    fn decode_funding(env: &Env, raw: &Val) -> Result<FundingData, Error> {
+        if Self::has_field(env, raw, "index") {
+            return FundingData::try_from_val(env, raw).map_err(|_| Error::MigrationPending);
+        }
+        let v1 = FundingDataV1::try_from_val(env, raw).map_err(|_| Error::MigrationPending)?;
+        Ok(FundingData { index: v1.rate, last_update: v1.last_update })
+    }
+
+    fn get_funding_data(env: &Env, symbol: &Symbol) -> FundingData {
+        Self::market_state(env, symbol).funding
+    }
+
+    fn get_mark_price(env: &Env, symbol: &Symbol) -> Result<i128, Error> {
+        if let Some(settlement) = Self::get_settlement(env, symbol) {
+            return Ok(settlement.price);
+        }
+        let oracle_price = Self::oracle_price(env, symbol)?;
+        let market = Self::market_state(env, symbol);
+        Ok(Self::compute_mark_price(oracle_price, market.net_oi, market.skew_scale))
+    }
+
+    fn oracle_price(env: &Env, symbol: &Symbol) -> Result<i128, Error> {
+        // Prefer mock price (used in unit tests); otherwise query the oracle.
+        if cfg!(test) {
+            return Self::_mock_oracle_price(env, symbol.clone());
+        }
+        fetch_oracle_price(env, symbol.clone())
+    }
+
+    // Mark price reusing oracle prices already fetched in this invocation.
+    // Net OI is always re-read, so the mark reflects trades made since.
+    // Settled markets are valued at their final price and never query the oracle
+    fn cached_mark_price(env: &Env, symbol: &Symbol, oracle_prices: &mut Map<Symbol, i128>) -> Result<i128, Error> {
+        if let Some(settlement) = Self::get_settlement(env, symbol) {
+            return Ok(settlement.price);
+        }
+        let oracle_price = Self::cached_oracle_price(env, symbol, oracle_prices)?;
+        let market = Self::market_state(env, symbol);
+        Ok(Self::compute_mark_price(oracle_price, market.net_oi, market.skew_scale))
+    }
+
+    fn cached_oracle_price(env: &Env, symbol: &Symbol, oracle_prices: &mut Map<Symbol, i128>) -> Result<i128, Error> {
+        if let Some(price) = oracle_prices.get(symbol.clone()) {
+            return Ok(price);
+        }
+        let price = Self::oracle_price(env, symbol)?;
+        oracle_prices.set(symbol.clone(), price);
+        Ok(price)
+    }
+
+    fn compute_mark_price(oracle_price: i128, net_oi: i128, skew_scale: i128) -> i128 {
+        // Avoid division by zero
+        if skew_scale == 0 { return oracle_price; }
+
+        // Premium / discount in basis points with overflow-safe clamping
+        let limit_oi = (skew_scale * MAX_DRIFT_BP) / 10_000;
+        let clamped_oi = net_oi.clamp(-limit_oi, limit_oi);
+        let pd_bp = (clamped_oi * 10_000) / skew_scale;
+        let adj_bp = pd_bp.clamp(-MAX_DRIFT_BP, MAX_DRIFT_BP);
+
+        // Mark price = oracle * (1 + adj_bp / 10_000)
+        (oracle_price * (10_000 + adj_bp)) / 10_000
+    }
+
+    // Funding index change over `elapsed` seconds at the given premium
+    fn funding_delta(oracle_price: i128, mark_price: i128, elapsed: u64) -> Result<i128, Error> {
+        if oracle_price == 0 {
+            return Ok(0);
+        }
+        let premium_bp = ((mark_price - oracle_price) * 10_000) / oracle_price;
+        // funding velocity proportional to premium, clamped to max drift
+        let capped_premium_bp = premium_bp.clamp(-MAX_DRIFT_BP, MAX_DRIFT_BP);
+
+        // Δrate = premium * maxVel * elapsed / (maxDrift * secondsPerDay)
+        let numerator = capped_premium_bp
+            .checked_mul(MAX_FUNDING_VEL_BP).ok_or(Error::Overflow)?
+            .checked_mul(elapsed as i128).ok_or(Error::Overflow)?;
+        let denominator: i128 = MAX_DRIFT_BP * 86_400;
+        Ok(numerator / denominator)
+    }
+
+    fn price_impact_bp(mark_before: i128, mark_after: i128) -> i128 {
+        if mark_before == 0 {
+            return 0;
+        }
+        ((mark_after - mark_before) * 10_000) / mark_before
+    }
+
+    fn plan_open(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        size: i128,
+        margin: i128,
+        limit_price: Option<i128>,
+    ) -> Result<OpenPlan, Error> {
+        if size == 0 || margin <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        Self::check_market(env, symbol, Operation::Open)?;
+        Self::validate_symbol(symbol)?;
+
+        let market = Self::market_state(env, symbol);
+        let oracle_price = Self::oracle_price(env, symbol)?;
+        let (net_oi, skew_scale) = (market.net_oi, market.skew_scale);
+        let mark_price = Self::compute_mark_price(oracle_price, net_oi, skew_scale);
+
+        // Slippage check
+        if let Some(limit_price) = limit_price {
+            if (size > 0 && mark_price > limit_price) || (size < 0 && mark_price < limit_price) {
+                return Err(Error::SlippageExceeded);
+            }
+        }
+
+        // Notional = |size| × price, guard against overflow
+        let notional = size.abs()
+            .checked_mul(mark_price)
+            .ok_or(Error::Overflow)? / DEC_P;
+
+        let position_key = DataKey::Position(trader.clone(), symbol.clone());
+        let existing = Self::load_position(env, &position_key);
+
+        // IMR comes from the tier of the resulting position. Crossing into a
+        // higher tier also tops up the existing notional to the new rate.
+        let existing_notional = existing.as_ref().map(|pos| pos.notional).unwrap_or(0);
+        let imr_bp = Self::margin_tier(env, symbol, existing_notional + notional).imr_bp;
+        let mut required_margin = (notional * imr_bp) / 10_000;
+        if existing_notional > 0 {
+            let existing_imr_bp = Self::margin_tier(env, symbol, existing_notional).imr_bp;
+            required_margin += (existing_notional * (imr_bp - existing_imr_bp)) / 10_000;
+        }
+
+        if margin < required_margin {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        // Funding accrued on an existing position is settled before its index resets
+        let funding = market.funding;
+        let funding_payment = existing.as_ref()
+            .map(|pos| Self::calculate_funding_payment(pos, &funding))
+            .unwrap_or(0);
+
+        let (reserve, fee) = Self::compute_reserves(&market.reserve, size, Self::fee_bp(env))?;
+
+        let free_collateral = Self::calculate_free_collateral(env, trader)?;
+        if margin > free_collateral - funding_payment - fee {
+            return Err(Error::InsufficientCollateral);
+        }
+
+        let new_net_oi = net_oi.checked_add(size).ok_or(Error::Overflow)?;
+        let net_notional = market.net_notional + Self::signed_notional(size, notional);
+
+        let old_size = existing.as_ref().map(|pos| pos.size).unwrap_or(0);
+        let position = match existing {
+            Some(mut pos) => {
+                pos.size += size;
+                pos.notional += notional;
+                pos.margin += margin;
+                pos.funding_index = funding.index;
+                pos.last_update = env.ledger().timestamp();
+                pos
+            }
+            None => Position {
+                size,
+                notional,
+                margin,
+                funding_index: funding.index,
+                last_update: env.ledger().timestamp(),
+            }
+        };
+
+        let current_oi = market.open_interest;
+        let open_interest = Self::open_interest_after(&current_oi, old_size, position.size);
+        Self::check_limits(env, symbol, &current_oi, &open_interest, old_size, position.size)?;
+
+        // Trades that grow the vault's net exposure must fit under the utilization cap
+        if new_net_oi.abs() > net_oi.abs() {
+            let mut oracle_prices = Map::new(env);
+            oracle_prices.set(symbol.clone(), oracle_price);
+            let (trader_pnl, _, exposure) =
+                Self::vault_exposure(env, &mut oracle_prices, Some((symbol, new_net_oi)))?;
+            let nav = Self::get_vault(env).balance - trader_pnl;
+            if exposure * 10_000 > nav * Self::get_vault_config(env).max_utilization_bp {
+                return Err(Error::UtilizationExceeded);
+            }
+        }
+
+        Ok(OpenPlan {
+            mark_price,
+            post_mark_price: Self::compute_mark_price(oracle_price, new_net_oi, skew_scale),
+            required_margin,
+            reserve,
+            fee,
+            net_oi: new_net_oi,
+            net_notional,
+            open_interest,
+            funding_payment,
+            position,
+        })
+    }
+
+    fn plan_close(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        size: i128,
+        limit_price: Option<i128>,
+    ) -> Result<ClosePlan, Error> {
+        if size == 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        Self::check_market(env, symbol, Operation::Close)?;
+
+        let position_key = DataKey::Position(trader.clone(), symbol.clone());
+        let mut position = Self::load_position(env, &position_key)
+            .ok_or(Error::PositionNotFound)?;
+
+        // Check if closing size exceeds position
+        if size.abs() > position.size.abs() {
+            return Err(Error::InvalidAmount);
+        }
+
+        let market = Self::market_state(env, symbol);
+        let oracle_price = Self::oracle_price(env, symbol)?;
+        let (net_oi, skew_scale) = (market.net_oi, market.skew_scale);
+        let mark_price = Self::compute_mark_price(oracle_price, net_oi, skew_scale);
+
+        // Slippage check – for reducing longs want min price, for reducing shorts want max
+        if let Some(limit_price) = limit_price {
+            if (size > 0 && mark_price < limit_price) || (size < 0 && mark_price > limit_price) {
+                return Err(Error::SlippageExceeded);
+            }
+        }
+
+        // Calculate PnL for the closing portion
+        let closing_notional = (size.abs() * mark_price) / DEC_P;
+        let original_notional = (position.notional * size.abs()) / position.size.abs();
+
+        let pnl = if (size > 0 && position.size > 0) || (size < 0 && position.size < 0) {
+            // Closing position (same direction)
+            if position.size > 0 {
+                closing_notional - original_notional
+            } else {
+                original_notional - closing_notional
+            }
+        } else {
+            // Flipping position
+            return Err(Error::InvalidAmount);
+        };
+
+        let (reserve, fee) = Self::compute_reserves(&market.reserve, -size, Self::fee_bp(env))?;
+        let new_net_oi = net_oi - size;
+        let net_notional = market.net_notional
+            - Self::signed_notional(position.size, original_notional);
+
+        // Calculate funding payment
+        let funding = market.funding;
+        let funding_payment = Self::calculate_funding_payment(&position, &funding);
+
+        let open_interest = Self::open_interest_after(
+            &market.open_interest, position.size, position.size - size,
+        );
+
+        // Settle PnL, funding and the fee. An isolated loss stops at the margin
+        // of the closed portion, a cross loss at the collateral unless token
+        // collateral can cover the debt; the vault absorbs the rest, as on liquidation
+        let margin_released = (position.margin * size.abs()) / position.size.abs();
+        let owed = pnl - funding_payment - fee;
+        let mut settlement = owed;
+        if Self::get_margin_mode(env, trader) == MarginMode::Isolated {
+            settlement = settlement.max(-margin_released);
+        } else if Self::token_collateral(env, trader).is_empty() {
+            settlement = settlement.max(-Self::get_collateral(env, trader).max(0));
+        }
+
+        // Update position
+        position.size -= size;
+        position.notional -= original_notional;
+        position.margin -= margin_released;
+        position.funding_index = funding.index;
+        position.last_update = env.ledger().timestamp();
+
+        Ok(ClosePlan {
+            mark_price,
+            post_mark_price: Self::compute_mark_price(oracle_price, new_net_oi, skew_scale),
+            reserve,
+            fee,
+            net_oi: new_net_oi,
+            net_notional,
+            open_interest,
+            pnl,
+            realized_pnl: pnl + settlement - owed,
+            funding_payment,
+            settlement,
+            position: if position.size == 0 { None } else { Some(position) },
+        })
+    }
+
+
+    // Growing either side of the book, or an account's position, must stay under the caps
+    fn check_limits(
+        env: &Env,
+        symbol: &Symbol,
+        current_oi: &OpenInterest,
+        open_interest: &OpenInterest,
+        old_size: i128,
+        new_size: i128,
+    ) -> Result<(), Error> {
+        let limits = Self::get_market_limits(env, symbol);
+        if (open_interest.long > current_oi.long && open_interest.long > limits.max_long_oi)
+            || (open_interest.short > current_oi.short && open_interest.short > limits.max_short_oi)
+            || (new_size.abs() > old_size.abs() && new_size.abs() > limits.max_position_size)
+        {
+            return Err(Error::OpenInterestCap);
+        }
+        Ok(())
+    }
+
+    // Constant-product swap of `size` base units; returns new reserves and the fee kept by the pool
+    fn compute_reserves(current: &Reserve, size: i128, fee_bp: i128) -> Result<(Reserve, i128), Error> {
+        let mut reserve = current.clone();
+        
+        // Capture pre-trade reserves for fee calculation
+        let base_before = reserve.base;
+        let quote_before = reserve.quote;
+
+        // Overflow-safe computation of constant product k
+        let k = base_before.checked_mul(quote_before).ok_or(Error::Overflow)?;
+
+        // Update reserves following constant-product invariant
+        let new_base = base_before.checked_sub(size).ok_or(Error::Overflow)?;
+        if new_base <= 0 {
+            return Err(Error::InvalidAmount);
+        }
+        reserve.base = new_base;
+        
+        reserve.quote = k / reserve.base;
+
+        // Calculate absolute change in quote ( |Δquote| ) without risking under-/overflow
+        let delta_quote = if reserve.quote >= quote_before {
+            reserve.quote - quote_before
+        } else {
+            quote_before - reserve.quote
+        };
+
+        // Fee = |Δquote| × feeBp / 10 000 with overflow checking
+        let fee = delta_quote
+            .checked_mul(fee_bp).ok_or(Error::Overflow)?
+            / 10_000;
+
+        // Pool retains the fee in quote asset – check for overflow
+        reserve.quote = reserve.quote.checked_add(fee).ok_or(Error::Overflow)?;
+        
+        Ok((reserve, fee))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn is_liquidatable(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        position: &Position,
+        mark_price: i128,
+        funding: &FundingData,
+        mode: MarginMode,
+        oracle_prices: &mut Map<Symbol, i128>,
+    ) -> Result<bool, Error> {
+        match mode {
+            MarginMode::Isolated => {
+                let current_notional = (position.size.abs() * mark_price) / DEC_P;
+                let mmr_bp = Self::margin_tier(env, symbol, current_notional).mmr_bp;
+                Ok(Self::calculate_margin_ratio(position, mark_price, funding) < mmr_bp)
+            }
+            MarginMode::Cross => {
+                let (equity, maintenance_margin) =
+                    Self::calculate_account_health_cached(env, trader, oracle_prices)?;
+                Ok(equity < maintenance_margin)
+            }
+        }
+    }
+
+    fn get_auction(env: &Env) -> LiquidationAuction {
+        env.storage().instance()
+            .get(&DataKey::LiquidationAuction)
+            .unwrap_or(LiquidationAuction {
+                min_bonus_bp: MIN_BONUS_BP,
+                max_bonus_bp: BONUS_BP,
+                ramp_seconds: BONUS_RAMP_SECS,
+            })
+    }
+
+    fn current_bonus_bp(env: &Env, trader: &Address, symbol: &Symbol) -> i128 {
+        let auction = Self::get_auction(env);
+        let start: u64 = match env.storage().persistent()
+            .get(&DataKey::LiquidationStart(trader.clone(), symbol.clone())) {
+            Some(start) => start,
+            None => return auction.min_bonus_bp,
+        };
+        let elapsed = env.ledger().timestamp().saturating_sub(start);
+        if auction.ramp_seconds == 0 || elapsed >= auction.ramp_seconds {
+            return auction.max_bonus_bp;
+        }
+        auction.min_bonus_bp
+            + ((auction.max_bonus_bp - auction.min_bonus_bp) * elapsed as i128) / auction.ramp_seconds as i128
+    }
+
+    // A trader touching the position resets any auction in progress
+    fn clear_liquidation_start(env: &Env, trader: &Address, symbol: &Symbol) {
+        env.storage().persistent().remove(&DataKey::LiquidationStart(trader.clone(), symbol.clone()));
+    }
+
+    // Cross positions share the account's health, so new collateral resets them all
+    fn clear_cross_liquidation_starts(env: &Env, trader: &Address) {
+        if Self::get_margin_mode(env, trader) != MarginMode::Cross {
+            return;
+        }
+        for symbol in Self::account(env, trader).markets {
+            Self::clear_liquidation_start(env, trader, &symbol);
+        }
+    }
+
+    // Everything a liquidation will write, computed up front so a failure
+    // leaves no partial state behind (`liquidate_batch` carries on after one)
+    #[allow(clippy::too_many_arguments)]
+    fn plan_liquidation(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        position: &Position,
+        mark_price: i128,
+        funding: &FundingData,
+        mode: MarginMode,
+    ) -> Result<LiquidationPlan, Error> {
+        // Calculate liquidation values
+        let current_notional = (position.size.abs() * mark_price) / DEC_P;
+        let bonus = (current_notional * Self::current_bonus_bp(env, trader, symbol)) / 10_000;
+
+        // Realise PnL, funding and the bonus against the trader. Isolated losses
+        // stop at the position's margin; cross losses draw on the whole account.
+        let pnl = Self::calculate_unrealized_pnl(position, mark_price);
+        let funding_paid = Self::calculate_funding_payment(position, funding);
+        let mut trader_delta = pnl - funding_paid - bonus;
+        let remaining_margin = (position.margin + trader_delta).max(0);
+        // A loss the cap forgives never leaves the trader's collateral, so it
+        // is not booked as realized either
+        let mut realized_pnl = pnl;
+        if mode == MarginMode::Isolated {
+            let capped = trader_delta.max(-position.margin);
+            realized_pnl += capped - trader_delta;
+            trader_delta = capped;
+        }
+
+        // Hand the position to the backstop if it can carry it, otherwise close it on the AMM
+        let mut market = Self::market_state(env, symbol);
+        let takeover = Self::plan_takeover(env, trader, symbol, position, mark_price, funding, remaining_margin)?;
+        if takeover.is_some() {
+            trader_delta -= remaining_margin;
+            // The backstop re-enters at the mark, replacing the trader's entry notional
+            market.net_notional += Self::signed_notional(position.size, current_notional - position.notional);
+        } else {
+            // Close against the AMM: update reserves, net OI and open interest
+            let (reserve, _fee) = Self::compute_reserves(&market.reserve, -position.size, Self::fee_bp(env))?;
+            market.reserve = reserve;
+            market.net_oi -= position.size;
+            market.net_notional -= Self::signed_notional(position.size, position.notional);
+            market.open_interest = Self::open_interest_after(&market.open_interest, position.size, 0);
+        }
+
+        Ok(LiquidationPlan {
+            current_notional,
+            bonus,
+            pnl,
+            realized_pnl,
+            funding_paid,
+            trader_delta,
+            remaining_margin,
+            market,
+            takeover,
+        })
+    }
+
+    // Applies a liquidation plan: closes `position`, settles it against the
+    // trader and pays the liquidator. Returns the bonus paid.
+    fn liquidate_position(
+        env: &Env,
+        liquidator: &Address,
+        trader: &Address,
+        symbol: &Symbol,
+        position: &Position,
+        mark_price: i128,
+        plan: LiquidationPlan,
+    ) -> i128 {
+        let LiquidationPlan {
+            current_notional,
+            bonus,
+            pnl,
+            realized_pnl,
+            funding_paid,
+            trader_delta,
+            remaining_margin,
+            market,
+            takeover,
+        } = plan;
+        Self::put_market_state(env, symbol, &market);
+
+        let mut to_backstop = 0i128;
+        let mut backstop = None;
+        if let Some(takeover) = takeover {
+            to_backstop = remaining_margin;
+            backstop = Some(takeover.backstop.clone());
+            Self::apply_takeover(env, trader, symbol, position, remaining_margin, takeover);
+        }
+
+        // Remove position
+        Self::put_position(env, trader, symbol, None);
+
+        // A shortfall stays on the account as debt while token collateral can
+        // cover it through `liquidate_collateral`; otherwise the vault absorbs it
+        let trader_collateral = Self::get_collateral(env, trader);
+        let mut new_trader_collateral = trader_collateral + trader_delta;
+        if Self::token_collateral(env, trader).is_empty() {
+            new_trader_collateral = new_trader_collateral.max(0);
+        }
+        Self::put_collateral(env, trader, new_trader_collateral);
+
+        // The vault is the counterparty: it absorbs whatever the trader's
+        // collateral does not cover, including bad debt and the bonus
+        let trader_change = new_trader_collateral - trader_collateral;
+        Self::adjust_vault_balance(env, -(trader_change + to_backstop + bonus));
+
+        // Bad debt the vault absorbs is likewise taken off the realized loss
+        let realized_pnl = realized_pnl + trader_change - trader_delta;
+        let losses = bonus + to_backstop;
+        Self::record(env, trader, symbol, Self::ledger_entry(realized_pnl, 0, funding_paid, losses, current_notional));
+
+        // Transfer bonus to liquidator
+        let liquidator_collateral = Self::get_collateral(env, liquidator);
+        Self::put_collateral(env, liquidator, liquidator_collateral + bonus);
+
+        let event = LiquidateEvent {
+            version: EVENT_VERSION,
+            liquidator: liquidator.clone(),
+            trader: trader.clone(),
+            symbol: symbol.clone(),
+            size: position.size,
+            mark_price,
+            pnl,
+            funding_paid,
+            bonus,
+            remaining_margin,
+            backstop,
+        };
+        env.events().publish((symbol_short!("LIQUIDATE"), trader.clone(), symbol.clone()), event);
+        Self::clear_liquidation_start(env, trader, symbol);
+
+        bonus
+    }
+
+    // Moves a liquidated position to the backstop at the mark price, carrying
+    // its remaining margin. The backstop tops the margin up to IMR from its
+    // own free collateral; None when no backstop is set, it holds the
+    // opposite side, the position would breach the market caps, or it
+    // cannot cover IMR.
+    fn plan_takeover(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        position: &Position,
+        mark_price: i128,
+        funding: &FundingData,
+        remaining_margin: i128,
+    ) -> Result<Option<Takeover>, Error> {
+        let backstop: Address = match env.storage().instance().get(&DataKey::Backstop) {
+            Some(backstop) => backstop,
+            None => return Ok(None),
+        };
+        if backstop == *trader {
+            return Ok(None);
+        }
+
+        let position_key = DataKey::Position(backstop.clone(), symbol.clone());
+        let existing = Self::load_position(env, &position_key);
+        if let Some(pos) = &existing {
+            if pos.size.signum() != position.size.signum() {
+                return Ok(None);
+            }
+        }
+
+        // The trader's open interest moves to the backstop, which must still fit the caps
+        let existing_size = existing.as_ref().map(|pos| pos.size).unwrap_or(0);
+        let current_oi = Self::market_state(env, symbol).open_interest;
+        let open_interest = Self::open_interest_after(&current_oi, position.size, 0);
+        let open_interest = Self::open_interest_after(&open_interest, existing_size, existing_size + position.size);
+        if Self::check_limits(env, symbol, &current_oi, &open_interest, existing_size, existing_size + position.size).is_err() {
+            return Ok(None);
+        }
+
+        let notional = (position.size.abs() * mark_price) / DEC_P;
+        let existing_notional = existing.as_ref().map(|pos| pos.notional).unwrap_or(0);
+        let imr_bp = Self::margin_tier(env, symbol, existing_notional + notional).imr_bp;
+        let required_margin = (notional * imr_bp) / 10_000;
+        let margin = remaining_margin.max(required_margin);
+        let funding_payment = existing.as_ref()
+            .map(|pos| Self::calculate_funding_payment(pos, funding))
+            .unwrap_or(0);
+
+        let free_collateral = Self::calculate_free_collateral(env, &backstop)?;
+        if margin - remaining_margin + funding_payment > free_collateral {
+            return Ok(None);
+        }
+
+        let position = match existing {
+            Some(mut pos) => {
+                pos.size += position.size;
+                pos.notional += notional;
+                pos.margin += margin;
+                pos.funding_index = funding.index;
+                pos.last_update = env.ledger().timestamp();
+                pos
+            }
+            None => Position {
+                size: position.size,
+                notional,
+                margin,
+                funding_index: funding.index,
+                last_update: env.ledger().timestamp(),
+            }
+        };
+        Ok(Some(Takeover { backstop, position, notional, funding_payment }))
+    }
+
+    fn apply_takeover(
+        env: &Env,
+        trader: &Address,
+        symbol: &Symbol,
+        position: &Position,
+        remaining_margin: i128,
+        takeover: Takeover,
+    ) {
+        let Takeover { backstop, position: inherited, notional, funding_payment } = takeover;
+        Self::put_position(env, &backstop, symbol, Some(&inherited));
+        Self::record(env, &backstop, symbol, Self::ledger_entry(0, 0, funding_payment, 0, notional));
+
+        // The trader's remaining margin moves with the position
+        let backstop_collateral = Self::get_collateral(env, &backstop);
+        Self::put_collateral(env, &backstop, backstop_collateral + remaining_margin - funding_payment);
+        Self::adjust_vault_balance(env, funding_payment);
+
+        let event = TakeoverEvent {
+            version: EVENT_VERSION,
+            trader: trader.clone(),
+            backstop,
+            symbol: symbol.clone(),
+            size: position.size,
+            notional,
+            remaining_margin,
+            position: inherited,
+        };
+        env.events().publish((symbol_short!("TAKEOVER"), trader.clone(), symbol.clone()), event);
+    }
+
+    fn get_vault(env: &Env) -> Vault {
+        env.storage().persistent()
+            .get(&DataKey::Vault)
+            .unwrap_or(Vault { balance: 0, total_shares: 0 })
+    }
+
+    fn put_vault(env: &Env, vault: &Vault) {
+        env.storage().persistent().set(&DataKey::Vault, vault);
+        Self::extend_persistent(env, &DataKey::Vault);
+    }
+
+    // Credits (or debits, if negative) the vault's cash balance
+    fn adjust_vault_balance(env: &Env, delta: i128) {
+        let mut vault = Self::get_vault(env);
+        vault.balance += delta;
+        Self::put_vault(env, &vault);
+    }
+
+    // Moves the protocol's share of a trading fee the vault was credited with to the treasury
+    fn take_protocol_fee(env: &Env, fee: i128) {
+        let share = (fee * Self::protocol_fee_bp(env)) / 10_000;
+        if share <= 0 {
+            return;
+        }
+        let token: Address = env.storage().instance().get(&DataKey::CollateralToken).unwrap();
+        Self::adjust_vault_balance(env, -share);
+        Self::credit_treasury(env, &token, share);
+    }
+
+    fn get_vault_config(env: &Env) -> VaultConfig {
         env.storage().instance()
-            .get(&DataKey::Admin)
-            .ok_or(Error::NotInitialized)
+            .get(&DataKey::VaultConfig)
+            .unwrap_or(VaultConfig {
+                max_utilization_bp: MAX_UTILIZATION_BP,
+                deposit_cooldown: LP_DEPOSIT_COOLDOWN,
+                withdraw_cooldown: LP_WITHDRAW_COOLDOWN,
+            })
     }
 
-    fn check_not_paused(env: &Env) -> Result<(), Error> {
-        let paused: bool = env.storage().instance()
-            .get(&DataKey::Paused)
-            .unwrap_or(false);
-        if paused {
-            Err(Error::Paused)
+    fn get_lp(env: &Env, lp: &Address) -> LpPosition {
+        env.storage().persistent()
+            .get(&DataKey::LpPosition(lp.clone()))
+            .unwrap_or(LpPosition { shares: 0, last_deposit: 0, pending_shares: 0, requested_at: 0 })
+    }
+
+    fn put_lp(env: &Env, lp: &Address, position: &LpPosition) {
+        let key = DataKey::LpPosition(lp.clone());
+        if position.shares == 0 {
+            env.storage().persistent().remove(&key);
         } else {
-            Ok(())
+            env.storage().persistent().set(&key, position);
+            Self::extend_persistent(env, &key);
         }
     }
 
-    fn validate_symbol(symbol: &Symbol) -> Result<(), Error> {
-        let valid_symbols = [
-            symbol_short!("XLM"),
-            symbol_short!("BTC"),
-            symbol_short!("ETH"),
-        ];
-        
-        if valid_symbols.contains(symbol) {
-            Ok(())
-        } else {
-            Err(Error::InvalidSymbol)
+    // Gross OI once a position of `old_size` becomes `new_size` (either may be 0)
+    fn open_interest_after(current: &OpenInterest, old_size: i128, new_size: i128) -> OpenInterest {
+        OpenInterest {
+            long: current.long - old_size.max(0) + new_size.max(0),
+            short: current.short - (-old_size).max(0) + (-new_size).max(0),
         }
     }
 
-    fn get_collateral(env: &Env, trader: &Address) -> i128 {
+    fn get_market_limits(env: &Env, symbol: &Symbol) -> MarketLimits {
         env.storage().persistent()
-            .get(&DataKey::Collateral(trader.clone()))
-            .unwrap_or(0)
+            .get(&DataKey::MarketLimits(symbol.clone()))
+            .unwrap_or(MarketLimits {
+                max_long_oi: i128::MAX,
+                max_short_oi: i128::MAX,
+                max_position_size: i128::MAX,
+            })
     }
 
-    fn get_reserves(env: &Env, symbol: &Symbol) -> Reserve {
+    // Markets without a schedule use the flat IMR_BP / MMR_BP at every size
+    fn margin_tiers(env: &Env, symbol: &Symbol) -> Vec<MarginTier> {
         env.storage().persistent()
-            .get(&DataKey::Reserves(symbol.clone()))
-            .unwrap_or(Reserve { base: 0, quote: 0 })
+            .get(&DataKey::MarginTiers(symbol.clone()))
+            .unwrap_or(vec![env, MarginTier { min_notional: 0, imr_bp: IMR_BP, mmr_bp: MMR_BP }])
     }
 
-    fn get_funding_data(env: &Env, symbol: &Symbol) -> FundingData {
+    // Highest tier whose threshold the notional reaches
+    fn margin_tier(env: &Env, symbol: &Symbol, notional: i128) -> MarginTier {
+        let tiers = Self::margin_tiers(env, symbol);
+        let mut selected = tiers.get_unchecked(0);
+        for tier in tiers.iter() {
+            if notional < tier.min_notional {
+                break;
+            }
+            selected = tier;
+        }
+        selected
+    }
+
+    // Current notional × the MMR of the tier that notional falls in
+    fn maintenance_margin(env: &Env, symbol: &Symbol, position: &Position, mark_price: i128) -> i128 {
+        let current_notional = (position.size.abs() * mark_price) / DEC_P;
+        (current_notional * Self::margin_tier(env, symbol, current_notional).mmr_bp) / 10_000
+    }
+
+    fn signed_notional(size: i128, notional: i128) -> i128 {
+        if size > 0 { notional } else { -notional }
+    }
+
+    fn vault_nav(env: &Env, vault: &Vault, oracle_prices: &mut Map<Symbol, i128>) -> Result<i128, Error> {
+        let (trader_pnl, _, _) = Self::vault_exposure(env, oracle_prices, None)?;
+        Ok(vault.balance - trader_pnl)
+    }
+
+    // (traders' aggregate unrealised PnL, the part of it traders are up by
+    // market, Σ |net OI| × mark) across markets. `pending` substitutes a
+    // post-trade net OI for one market's exposure.
+    fn vault_exposure(
+        env: &Env,
+        oracle_prices: &mut Map<Symbol, i128>,
+        pending: Option<(&Symbol, i128)>,
+    ) -> Result<(i128, i128, i128), Error> {
+        let mut trader_pnl = 0i128;
+        let mut trader_gains = 0i128;
+        let mut exposure = 0i128;
+
+        for symbol in Self::supported_symbols(env) {
+            let market = Self::market_state(env, &symbol);
+            let (net_oi, net_notional) = (market.net_oi, market.net_notional);
+            let exposed_oi = match pending {
+                Some((pending_symbol, pending_oi)) if *pending_symbol == symbol => pending_oi,
+                _ => net_oi,
+            };
+            if net_oi == 0 && net_notional == 0 && exposed_oi == 0 {
+                continue;
+            }
+
+            let mark_price = Self::cached_mark_price(env, &symbol, oracle_prices)?;
+            let skew_value = net_oi.checked_mul(mark_price).ok_or(Error::Overflow)? / DEC_P;
+            trader_pnl += skew_value - net_notional;
+            trader_gains += (skew_value - net_notional).max(0);
+            exposure += exposed_oi.checked_mul(mark_price).ok_or(Error::Overflow)?.abs() / DEC_P;
+        }
+
+        Ok((trader_pnl, trader_gains, exposure))
+    }
+
+    fn get_margin_mode(env: &Env, trader: &Address) -> MarginMode {
         env.storage().persistent()
-            .get(&DataKey::Funding(symbol.clone()))
-            .unwrap_or(FundingData { rate: 0, last_update: 0 })
+            .get(&DataKey::MarginMode(trader.clone()))
+            .unwrap_or(MarginMode::Isolated)
+    }
+
+    fn calculate_free_collateral(env: &Env, trader: &Address) -> Result<i128, Error> {
+        let account = Self::account(env, trader);
+        let token_value = Self::token_collateral_value(env, trader, &mut Map::new(env))?;
+        if Self::get_margin_mode(env, trader) == MarginMode::Isolated {
+            return Ok(account.collateral + token_value - account.margin_used);
+        }
+
+        let mut unrealized = 0i128;
+        for symbol in account.markets {
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            if let Some(position) = Self::load_position(env, &position_key) {
+                let mark_price = Self::get_mark_price(env, &symbol)?;
+                let funding = Self::get_funding_data(env, &symbol);
+                unrealized += Self::calculate_unrealized_pnl(&position, mark_price)
+                    - Self::calculate_funding_payment(&position, &funding);
+            }
+        }
+
+        // Cross accounts cannot withdraw against losses carried by their positions
+        Ok(account.collateral + token_value - account.margin_used + unrealized.min(0))
+    }
+
+    // (equity, maintenance margin) of the whole account at current mark prices
+    fn calculate_account_health(env: &Env, trader: &Address) -> Result<(i128, i128), Error> {
+        Self::calculate_account_health_cached(env, trader, &mut Map::new(env))
+    }
+
+    fn calculate_account_health_cached(
+        env: &Env,
+        trader: &Address,
+        oracle_prices: &mut Map<Symbol, i128>,
+    ) -> Result<(i128, i128), Error> {
+        let account = Self::account(env, trader);
+        let mut equity = account.collateral + Self::token_collateral_value(env, trader, oracle_prices)?;
+        let mut maintenance_margin = 0i128;
+
+        for symbol in account.markets {
+            let position_key = DataKey::Position(trader.clone(), symbol.clone());
+            if let Some(position) = Self::load_position(env, &position_key) {
+                let mark_price = Self::cached_mark_price(env, &symbol, oracle_prices)?;
+                let funding = Self::get_funding_data(env, &symbol);
+                equity += Self::calculate_unrealized_pnl(&position, mark_price)
+                    - Self::calculate_funding_payment(&position, &funding);
+                maintenance_margin += Self::maintenance_margin(env, &symbol, &position, mark_price);
+            }
+        }
+
+        Ok((equity, maintenance_margin))
+    }
+
+    fn calculate_unrealized_pnl(position: &Position, mark_price: i128) -> i128 {
+        let current_notional = (position.size.abs() * mark_price) / DEC_P;
+        if position.size > 0 {
+            current_notional - position.notional
+        } else {
+            position.notional - current_notional
+        }
+    }
+
+    // Margin ratio = (margin + uPnL − accrued funding) / current notional, in bp
+    fn calculate_margin_ratio(position: &Position, mark_price: i128, funding: &FundingData) -> i128 {
+        let current_notional = (position.size.abs() * mark_price) / DEC_P;
+        if current_notional == 0 {
+            return 10_000; // 100%
+        }
+        let equity = position.margin
+            + Self::calculate_unrealized_pnl(position, mark_price)
+            - Self::calculate_funding_payment(position, funding);
+        (equity * 10_000) / current_notional
+    }
+
+    // Mark price at which `calculate_margin_ratio` reaches `mmr_bp`. Solving
+    //   long:  margin − notional − f + s·p = mmr·s·p  →  p = (notional + f − margin) / (s·(1 − mmr))
+    //   short: margin + notional − f − s·p = mmr·s·p  →  p = (margin + notional − f) / (s·(1 + mmr))
+    // Callers pass the MMR of the position's current tier, so the price is
+    // approximate if reaching it would move the position into another tier.
+    // Returns 0 when no positive price can trigger liquidation.
+    fn calculate_liquidation_price(position: &Position, funding: &FundingData, mmr_bp: i128) -> i128 {
+        let size_abs = position.size.abs();
+        if size_abs == 0 {
+            return 0;
+        }
+        let funding_owed = Self::calculate_funding_payment(position, funding);
+        let (numerator, denominator_bp) = if position.size > 0 {
+            (position.notional + funding_owed - position.margin, 10_000 - mmr_bp)
+        } else {
+            (position.margin + position.notional - funding_owed, 10_000 + mmr_bp)
+        };
+        if numerator <= 0 {
+            return 0;
+        }
+        (numerator * DEC_P * 10_000) / (size_abs * denominator_bp)
+    }
+
+    fn calculate_funding_payment(position: &Position, funding: &FundingData) -> i128 {
+        let funding_diff = funding.index - position.funding_index;
+        (position.size * funding_diff) / DEC_F
+    }
+
+    // Mock prices for testing - remove when using real oracle. Tests can
+    // override a market's price by writing (MOCK_PX, symbol) to temporary storage.
+    #[cfg(test)]
+    fn _mock_oracle_price(env: &Env, symbol: Symbol) -> Result<i128, Error> {
+        // A non-positive mock price simulates a dead feed
+        if let Some(price) = env.storage().temporary().get::<_, i128>(&(symbol_short!("MOCK_PX"), symbol.clone())) {
+            return if price > 0 { Ok(price) } else { Err(Error::OracleUnavailable) };
+        }
+        match symbol {
+            s if s == symbol_short!("XLM") => Ok(100_000), // $0.10
+            s if s == symbol_short!("BTC") => Ok(100_000_000_000), // $100,000
+            s if s == symbol_short!("ETH") => Ok(4_000_000_000), // $4,000
+            _ => Err(Error::InvalidSymbol),
+        }
+    }
+
+    #[cfg(not(test))]
+    fn _mock_oracle_price(_env: &Env, _symbol: Symbol) -> Result<i128, Error> {
+        Err(Error::OracleUnavailable)
+    }
+
+    // ---------------- Permissionless funding keeper ----------------
+    pub fn poke_funding(env: Env, symbol: Symbol) -> Result<(), Error> {
+        Self::bump_instance(&env);
+        Self::check_market(&env, &symbol, Operation::Funding)?;
+        Self::validate_symbol(&symbol)?;
+
+        let mut market = Self::market_state(&env, &symbol);
+        let now = env.ledger().timestamp();
+        if now - market.funding.last_update < FUNDING_PERIOD {
+            return Ok(()); // ignore early calls
+        }
+
+        // Reuse oracle_price to compute mark price without another oracle call
+        let oracle_price = Self::oracle_price(&env, &symbol)?;
+        let mark_price = Self::compute_mark_price(oracle_price, market.net_oi, market.skew_scale);
+
+        let delta_rate = Self::funding_delta(oracle_price, mark_price, now - market.funding.last_update)?;
+
+        market.funding.index += delta_rate;
+        market.funding.last_update = now;
+        Self::put_market_state(&env, &symbol, &market);
+
+        let event = FundingEvent {
+            version: EVENT_VERSION,
+            symbol: symbol.clone(),
+            delta: delta_rate,
+            index: market.funding.index,
+            oracle_price,
+            mark_price,
+            timestamp: now,
+        };
+        env.events().publish((symbol_short!("FUNDING"), symbol), event);
+        Ok(())
+    }
+}
+
+// Tests
+#[cfg(test)]
+#[allow(clippy::let_unit_value)]
+mod test {
+    use super::*;
+    use soroban_sdk::testutils::storage::{Instance as _, Persistent as _};
+    use soroban_sdk::testutils::{Address as _, Events, Ledger};
+    use soroban_sdk::token::StellarAssetClient;
+    use soroban_sdk::{map, IntoVal};
+
+    // A registered and initialized contract with a funded LP vault
+    fn setup(env: &Env) -> (Address, FlashPerpClient<'_>, Address, Address) {
+        env.mock_all_auths();
+
+        let contract_id = env.register(FlashPerp, ());
+        let client = FlashPerpClient::new(env, &contract_id);
+
+        let admin = Address::generate(env);
+        let token = Address::generate(env);
+
+        let _ = client.initialize(&admin, &token);
+        let _ = client.lp_deposit(&admin, &1_000_000_000);
+        (contract_id, client, admin, token)
+    }
+
+    fn set_mock_price(env: &Env, contract_id: &Address, symbol: &Symbol, price: i128) {
+        env.as_contract(contract_id, || {
+            env.storage().temporary().set(&(symbol_short!("MOCK_PX"), symbol.clone()), &price);
+        });
+    }
+
+    // Store a market the way schema v1 did: one entry per field, v1 funding
+    // layout, and no net notional or gross open interest
+    fn store_legacy_market(env: &Env, contract_id: &Address, symbol: &Symbol) {
+        env.as_contract(contract_id, || {
+            let storage = env.storage().persistent();
+            let Some(market) = storage.get::<DataKey, MarketState>(&DataKey::Market(symbol.clone())) else {
+                return; // already split
+            };
+            storage.remove(&DataKey::Market(symbol.clone()));
+            storage.set(&DataKey::Reserves(symbol.clone()), &market.reserve);
+            let funding = FundingDataV1 { rate: market.funding.index, last_update: market.funding.last_update };
+            storage.set(&DataKey::Funding(symbol.clone()), &funding);
+            storage.set(&DataKey::NetOi(symbol.clone()), &market.net_oi);
+            storage.set(&DataKey::SkewScale(symbol.clone()), &market.skew_scale);
+        });
+    }
+
+    // Rewrite everything the way the v1 contract stored it: collateral and
+    // v1 positions per trader, per-field markets, a pause flag, and no
+    // schema version, status, account records or running totals
+    fn store_legacy_state(env: &Env, contract_id: &Address, traders: &[&Address]) {
+        for symbol in [symbol_short!("XLM"), symbol_short!("BTC"), symbol_short!("ETH")] {
+            store_legacy_market(env, contract_id, &symbol);
+        }
+        env.as_contract(contract_id, || {
+            let storage = env.storage().persistent();
+            for trader in traders {
+                let account: Account = storage.get(&DataKey::Account((*trader).clone())).unwrap();
+                storage.remove(&DataKey::Account((*trader).clone()));
+                storage.set(&DataKey::Collateral((*trader).clone()), &account.collateral);
+                for symbol in account.markets {
+                    let key = DataKey::Position((*trader).clone(), symbol);
+                    let position: Position = storage.get(&key).unwrap();
+                    let v1 = PositionV1 {
+                        size: position.size,
+                        notional: position.notional,
+                        margin: position.margin,
+                        funding_index: position.funding_index,
+                    };
+                    storage.set(&key, &v1);
+                }
+            }
+            env.storage().instance().remove(&DataKey::SchemaVersion);
+            env.storage().instance().remove(&DataKey::TotalCollateral);
+            env.storage().instance().remove(&DataKey::GlobalStatus);
+            env.storage().instance().set(&DataKey::Paused, &false);
+        });
+    }
+
+    // Queue a change, wait out the timelock and execute it
+    fn apply_change(env: &Env, client: &FlashPerpClient, caller: &Address, change: ParamChange) {
+        let id = client.queue_change(caller, &change);
+        let delay = client.get_timelock_delay();
+        env.ledger().with_mut(|l| l.timestamp += delay);
+        client.execute_change(&id);
+    }
+
+
+    #[test]
+    fn test_initialization() {
+        let env = Env::default();
+        let contract_id = env.register(FlashPerp, ());
+        let client = FlashPerpClient::new(&env, &contract_id);
+
+        let admin = Address::generate(&env);
+        let token = Address::generate(&env);
+        let result = client.initialize(&admin, &token);
+        assert_eq!(result, ());
+
+        // Test double initialization
+        let result2 = client.try_initialize(&admin, &token);
+        assert_eq!(result2, Err(Ok(Error::AlreadyInitialized)));
+    }
+
+    #[test]
+    fn test_deposit_withdraw() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+
+        // Deposit
+        let _ = client.deposit_collateral(&trader, &1_000_000_000);
+        assert_eq!(client.get_free_collateral(&trader), 1_000_000_000);
+
+        // Withdraw
+        let _ = client.withdraw_collateral(&trader, &300_000_000);
+        assert_eq!(client.get_free_collateral(&trader), 700_000_000);
+    }
+
+    #[test]
+    fn test_open_close_position() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&trader, &10_000_000_000); // 10k USDC
+
+        // Determine current mark price for slippage limit
+        let mark = client.get_mark_price_view(&symbol);
+
+        // Open long position
+        let _ = client.open_position(&trader, &symbol, &10_000_000, &2_000_000_000, &mark); // limit = current price
+
+        let position = client.get_position(&trader, &symbol).unwrap();
+        assert_eq!(position.size, 10_000_000);
+        assert_eq!(position.margin, 2_000_000_000);
+
+        let mark2 = client.get_mark_price_view(&symbol);
+        // Close half with limit
+        let _ = client.close_position(&trader, &symbol, &5_000_000, &mark2);
+
+        let position = client.get_position(&trader, &symbol).unwrap();
+        assert_eq!(position.size, 5_000_000);
+
+        // --- Slippage failure case ---
+        let bad_limit = mark - 1; // for long position, too low
+        let res = client.try_open_position(&trader, &symbol, &1_000_000, &200_000_000, &bad_limit);
+        assert_eq!(res, Err(Ok(Error::SlippageExceeded)));
+    }
+
+    #[test]
+    fn test_account_view() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&trader, &10_000_000_000);
+
+        let empty = client.get_account(&trader);
+        assert_eq!(empty.collateral, 10_000_000_000);
+        assert_eq!(empty.positions.len(), 0);
+
+        let mark = client.get_mark_price_view(&symbol);
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &3_000_000, &mark);
+
+        let account = client.get_account(&trader);
+        assert_eq!(account.free_collateral, client.get_free_collateral(&trader));
+        assert_eq!(account.positions.len(), 1);
+
+        let view = account.positions.get(0).unwrap();
+        assert_eq!(view.symbol, symbol);
+        assert_eq!(view.entry_price, mark);
+        assert_eq!(view.mark_price, client.get_mark_price_view(&symbol));
+
+        let current_notional = (view.size.abs() * view.mark_price) / DEC_P;
+        let expected_ratio = ((view.margin + view.unrealized_pnl - view.accrued_funding) * 10_000) / current_notional;
+        assert_eq!(view.margin_ratio, expected_ratio);
+
+        // Long with 30% margin: liquidation sits below entry, where equity / notional = MMR
+        assert!(view.liquidation_price > 0 && view.liquidation_price < view.entry_price);
+        let liq_notional = (view.size * view.liquidation_price) / DEC_P;
+        let liq_equity = view.margin + liq_notional - view.notional;
+        assert!((liq_equity * 10_000 / liq_notional - MMR_BP).abs() <= 1);
+    }
+
+    #[test]
+    fn test_trade_quotes() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&trader, &10_000_000_000);
+
+        // Quotes surface the same error as the real call
+        let res = client.try_quote_open(&trader, &symbol, &100_000_000, &1_000);
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+        let res = client.try_quote_close(&trader, &symbol, &100_000_000);
+        assert_eq!(res, Err(Ok(Error::PositionNotFound)));
+
+        let quote = client.quote_open(&trader, &symbol, &100_000_000, &3_000_000);
+        assert_eq!(quote.fill_price, client.get_mark_price_view(&symbol));
+        assert_eq!(quote.required_margin, 2_000_000);
+        assert!(quote.price_impact_bp > 0);
+
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &3_000_000, &quote.fill_price);
+        let mark_after = client.get_mark_price_view(&symbol);
+        assert_eq!(quote.price_impact_bp, (mark_after - quote.fill_price) * 10_000 / quote.fill_price);
+        let view = client.get_account(&trader).positions.get(0).unwrap();
+        assert_eq!(quote.margin_ratio, view.margin_ratio);
+
+        let collateral_before = client.get_account(&trader).collateral;
+        let close_quote = client.quote_close(&trader, &symbol, &100_000_000);
+        assert_eq!(close_quote.fill_price, mark_after);
+        assert_eq!(close_quote.required_margin, 0);
+
+        let _ = client.close_position(&trader, &symbol, &100_000_000, &close_quote.fill_price);
+        let collateral_after = client.get_account(&trader).collateral;
+        assert_eq!(
+            collateral_after - collateral_before,
+            close_quote.realized_pnl - close_quote.funding_payment - close_quote.fee
+        );
+    }
+
+    #[test]
+    fn test_adjust_margin() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&trader, &10_000_000);
+
+        let res = client.try_add_margin(&trader, &symbol, &1_000_000);
+        assert_eq!(res, Err(Ok(Error::PositionNotFound)));
+
+        let mark = client.get_mark_price_view(&symbol);
+        let fee = client.quote_open(&trader, &symbol, &100_000_000, &3_000_000).fee;
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &3_000_000, &mark);
+        assert_eq!(client.get_free_collateral(&trader), 7_000_000 - fee);
+
+        // Top up from free collateral
+        env.ledger().with_mut(|l| l.timestamp += 60);
+        let _ = client.add_margin(&trader, &symbol, &2_000_000);
+        let position = client.get_position(&trader, &symbol).unwrap();
+        assert_eq!((position.margin, position.last_update), (5_000_000, env.ledger().timestamp()));
+        assert_eq!(client.get_free_collateral(&trader), 5_000_000 - fee);
+
+        let res = client.try_add_margin(&trader, &symbol, &6_000_000);
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+
+        // Pulling margin below IMR at the current mark is refused
+        let res = client.try_remove_margin(&trader, &symbol, &4_000_000);
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+
+        env.ledger().with_mut(|l| l.timestamp += 60);
+        let _ = client.remove_margin(&trader, &symbol, &2_500_000);
+        assert_eq!(client.get_position(&trader, &symbol).unwrap().margin, 2_500_000);
+        assert_eq!(client.get_free_collateral(&trader), 7_500_000 - fee);
+        let view = client.get_account(&trader).positions.get(0).unwrap();
+        assert!(view.margin_ratio >= IMR_BP);
+        assert_eq!(view.last_update, env.ledger().timestamp());
+    }
+
+    #[test]
+    fn test_isolated_and_cross_liquidation() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let isolated = Address::generate(&env);
+        let cross = Address::generate(&env);
+        let closer = Address::generate(&env);
+        let cross_closer = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&isolated, &3_000_000);
+        let _ = client.deposit_collateral(&cross, &3_000_000);
+        let _ = client.deposit_collateral(&closer, &3_000_000);
+        let _ = client.deposit_collateral(&cross_closer, &3_000_000);
+        let _ = client.set_margin_mode(&cross_closer, &MarginMode::Cross);
+        assert_eq!(client.get_margin_mode_view(&isolated), MarginMode::Isolated);
+
+        // Switching modes has its own pause flag, separate from add_margin
+        let _ = client.set_operation_paused(&admin, &Operation::MarginMode, &true);
+        let res = client.try_set_margin_mode(&cross, &MarginMode::Cross);
+        assert_eq!(res, Err(Ok(Error::Paused)));
+        let _ = client.set_operation_paused(&admin, &Operation::MarginMode, &false);
+        let _ = client.set_operation_paused(&admin, &Operation::AddMargin, &true);
+        let _ = client.set_margin_mode(&cross, &MarginMode::Cross);
+        let _ = client.set_operation_paused(&admin, &Operation::AddMargin, &false);
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&isolated, &symbol, &100_000_000, &2_100_000, &limit);
+        let _ = client.open_position(&cross, &symbol, &100_000_000, &2_100_000, &limit);
+        let fee = client.quote_open(&closer, &symbol, &100_000_000, &2_100_000).fee;
+        let _ = client.open_position(&closer, &symbol, &100_000_000, &2_100_000, &limit);
+        let _ = client.open_position(&cross_closer, &symbol, &100_000_000, &2_100_000, &limit);
+
+        let res = client.try_set_margin_mode(&cross, &MarginMode::Isolated);
+        assert_eq!(res, Err(Ok(Error::PositionsOpen)));
+
+        // -15%: the isolated position is under MMR on its own margin, while the
+        // cross account still has enough equity to cover maintenance.
+        set_mock_price(&env, &contract_id, &symbol, 85_000);
+        let res = client.try_liquidate(&liquidator, &cross, &symbol);
+        assert_eq!(res, Err(Ok(Error::BelowMaintenanceMargin)));
+
+        let view = client.get_account(&isolated).positions.get(0).unwrap();
+        assert!(view.margin_ratio < MMR_BP);
+        let _ = client.liquidate(&liquidator, &isolated, &symbol);
+        assert!(client.get_position(&isolated, &symbol).is_none());
+        // Isolated loss is capped at the position's margin
+        let remaining = client.get_account(&isolated).collateral;
+        assert!((900_000..3_000_000).contains(&remaining));
+
+        // -30%: account equity drops below maintenance, the cross position goes
+        let account = client.get_account(&cross);
+        assert!(account.positions.get(0).unwrap().liquidation_price > 70_000);
+        set_mock_price(&env, &contract_id, &symbol, 70_000);
+        let account = client.get_account(&cross);
+        assert!(account.equity < account.maintenance_margin);
+        let _ = client.liquidate(&liquidator, &cross, &symbol);
+        assert!(client.get_position(&cross, &symbol).is_none());
+        assert_eq!(client.get_account(&cross).collateral, 0);
+
+        // -50%: closing an isolated position past its margin also stops at
+        // the margin, the vault absorbs the rest
+        set_mock_price(&env, &contract_id, &symbol, 50_000);
+        let _ = client.close_position(&closer, &symbol, &100_000_000, &0);
+        assert_eq!(client.get_account(&closer).collateral, 3_000_000 - fee - 2_100_000);
+        let ledger = client.get_trade_ledger(&closer, &symbol);
+        assert_eq!(ledger.realized_pnl - ledger.fees_paid, -fee - 2_100_000);
+
+        // Closing a cross account underwater stops at its collateral; the
+        // vault writes off the shortfall instead of leaving a debt
+        let account = client.get_account(&cross_closer);
+        assert!(account.equity < 0);
+        let vault_before = client.get_vault_view().balance;
+        let _ = client.close_position(&cross_closer, &symbol, &100_000_000, &0);
+        assert_eq!(client.get_account(&cross_closer).collateral, 0);
+        assert_eq!(client.get_vault_view().balance, vault_before + account.collateral);
+        let ledger = client.get_trade_ledger(&cross_closer, &symbol);
+        assert_eq!(ledger.realized_pnl - ledger.fees_paid, -3_000_000);
     }
 
-    fn get_mark_price(env: &Env, symbol: &Symbol) -> Result<i128, Error> {
-        // Prefer mock price (used in unit tests); if unavailable, fall back to oracle.
-        #[cfg(test)]
-        let oracle_price = Self::_mock_oracle_price(symbol.clone())?;
+    #[test]
+    fn test_liquidate_account() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+        let eth = symbol_short!("ETH");
+
+        let _ = client.deposit_collateral(&trader, &12_000_000);
+
+        let res = client.try_liquidate_account(&liquidator, &trader);
+        assert_eq!(res, Err(Ok(Error::NotCrossMargin)));
+
+        let _ = client.set_margin_mode(&trader, &MarginMode::Cross);
+        let _ = client.open_position(&trader, &eth, &10_000, &8_000_000, &(client.get_mark_price_view(&eth) * 2));
+        let _ = client.open_position(&trader, &xlm, &100_000_000, &2_000_000, &(client.get_mark_price_view(&xlm) * 2));
+
+        let res = client.try_liquidate_account(&liquidator, &trader);
+        assert_eq!(res, Err(Ok(Error::BelowMaintenanceMargin)));
+
+        // ETH -25% sinks the whole account; closing ETH alone restores health
+        set_mock_price(&env, &contract_id, &eth, 3_000_000_000);
+        let account = client.get_account(&trader);
+        assert!(account.equity < account.maintenance_margin);
+
+        // With every market halted there is nothing to close
+        let _ = client.set_market_status(&admin, &xlm, &MarketStatus::Halted);
+        let _ = client.set_market_status(&admin, &eth, &MarketStatus::Halted);
+        let res = client.try_liquidate_account(&liquidator, &trader);
+        assert_eq!(res, Err(Ok(Error::Paused)));
+
+        // A halted market's position is skipped rather than failing the call
+        let _ = client.set_market_status(&admin, &eth, &MarketStatus::Active);
+        let closed = client.liquidate_account(&liquidator, &trader);
+        assert_eq!(closed, vec![&env, eth.clone()]);
+        assert!(client.get_position(&trader, &eth).is_none());
+        assert!(client.get_position(&trader, &xlm).is_some());
+
+        let account = client.get_account(&trader);
+        assert!(account.equity >= account.maintenance_margin);
+        // Unflagged, so the auction minimum of 0.25% on the ETH notional at the 3 000.30 mark
+        assert_eq!(client.get_account(&liquidator).collateral, 75_007);
+    }
 
-        #[cfg(not(test))]
-        let oracle_price = fetch_oracle_price(env, symbol.clone())?;
+    #[test]
+    fn test_liquidate_batch() {
+        let env = Env::default();
+        let (contract_id, client, _, _) = setup(&env);
+        let healthy = Address::generate(&env);
+        let underwater = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        // 2. Fetch net open interest and skew scale
-        let net_oi: i128 = env.storage().persistent()
-            .get::<DataKey, i128>(&DataKey::NetOi(symbol.clone()))
-            .unwrap_or(0);
-        let skew_scale: i128 = env.storage().persistent()
-            .get::<DataKey, i128>(&DataKey::SkewScale(symbol.clone()))
-            .unwrap_or(1);
+        let _ = client.deposit_collateral(&healthy, &10_000_000);
+        let _ = client.deposit_collateral(&underwater, &10_000_000);
 
-        // Avoid division by zero
-        if skew_scale == 0 { return Ok(oracle_price); }
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&healthy, &symbol, &100_000_000, &8_000_000, &limit);
+        let _ = client.open_position(&underwater, &symbol, &100_000_000, &2_100_000, &limit);
 
-        // 3. Premium / discount in basis points with overflow-safe clamping
-        let limit_oi = (skew_scale * MAX_DRIFT_BP) / 10_000;
-        let clamped_oi = net_oi.max(-limit_oi).min(limit_oi);
-        let pd_bp = (clamped_oi * 10_000) / skew_scale;
-        let adj_bp = pd_bp.max(-MAX_DRIFT_BP).min(MAX_DRIFT_BP);
+        set_mock_price(&env, &contract_id, &symbol, 85_000);
 
-        // 4. Mark price = oracle * (1 + adj_bp / 10_000)
-        Ok((oracle_price * (10_000 + adj_bp)) / 10_000)
-    }
+        let entries = vec![
+            &env,
+            (healthy.clone(), symbol.clone()),
+            (underwater.clone(), symbol.clone()),
+            (underwater.clone(), symbol.clone()),
+            (liquidator.clone(), symbol.clone()),
+        ];
+        let results = client.liquidate_batch(&liquidator, &entries);
 
-    fn update_reserves(env: &Env, symbol: &Symbol, size: i128) -> Result<(), Error> {
-        let mut reserve = Self::get_reserves(env, symbol);
-        
-        // Capture pre-trade reserves for fee calculation
-        let base_before = reserve.base;
-        let quote_before = reserve.quote;
+        assert_eq!(results.get(0).unwrap(), LiquidationResult::Healthy);
+        assert!(matches!(results.get(1).unwrap(), LiquidationResult::Liquidated(bonus) if bonus > 0));
+        assert_eq!(results.get(2).unwrap(), LiquidationResult::NoPosition);
+        assert_eq!(results.get(3).unwrap(), LiquidationResult::Failed(Error::SelfLiquidation as u32));
 
-        // Overflow-safe computation of constant product k
-        let k = base_before.checked_mul(quote_before).ok_or(Error::Overflow)?;
+        assert!(client.get_position(&healthy, &symbol).is_some());
+        assert!(client.get_position(&underwater, &symbol).is_none());
+    }
 
-        // Update reserves following constant-product invariant
-        let new_base = base_before.checked_sub(size).ok_or(Error::Overflow)?;
-        if new_base <= 0 {
-            return Err(Error::InvalidAmount);
+    #[test]
+    fn test_liquidate_batch_failed_entry() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let first = Address::generate(&env);
+        let stuck = Address::generate(&env);
+        let last = Address::generate(&env);
+        let backstop = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+        let btc = symbol_short!("BTC");
+        let eth = symbol_short!("ETH");
+
+        for trader in [&first, &stuck, &last] {
+            let _ = client.deposit_collateral(trader, &10_000_000);
         }
-        reserve.base = new_base;
-        
-        reserve.quote = k / reserve.base;
+        let _ = client.deposit_collateral(&backstop, &50_000_000);
+        let _ = client.set_margin_mode(&backstop, &MarginMode::Cross);
+        let _ = client.set_backstop(&admin, &Some(backstop.clone()));
+
+        let xlm_limit = client.get_mark_price_view(&xlm) * 2;
+        let btc_limit = client.get_mark_price_view(&btc) * 2;
+        let eth_limit = client.get_mark_price_view(&eth) * 2;
+        let _ = client.open_position(&first, &xlm, &100_000_000, &2_100_000, &xlm_limit);
+        let _ = client.open_position(&stuck, &btc, &100, &2_100_000, &btc_limit);
+        let _ = client.open_position(&last, &xlm, &100_000_000, &2_100_000, &xlm_limit);
+
+        // The backstop sits on the other side of XLM, so it only bids for the
+        // BTC position, and cannot value its own account once ETH goes dark
+        let _ = client.open_position(&backstop, &xlm, &-10_000_000, &1_000_000, &0);
+        let _ = client.open_position(&backstop, &eth, &1_000, &1_000_000, &eth_limit);
+
+        set_mock_price(&env, &contract_id, &xlm, 85_000);
+        set_mock_price(&env, &contract_id, &btc, 85_000_000_000);
+        set_mock_price(&env, &contract_id, &eth, 0);
+
+        let stuck_position = client.get_position(&stuck, &btc).unwrap();
+        let stuck_collateral = client.get_account(&stuck).collateral;
+        let btc_market = env.as_contract(&contract_id, || FlashPerp::market_state(&env, &btc));
+
+        let entries = vec![
+            &env,
+            (first.clone(), xlm.clone()),
+            (stuck.clone(), btc.clone()),
+            (last.clone(), xlm.clone()),
+        ];
+        let results = client.liquidate_batch(&liquidator, &entries);
+
+        assert!(matches!(results.get(0).unwrap(), LiquidationResult::Liquidated(_)));
+        assert_eq!(results.get(1).unwrap(), LiquidationResult::Failed(Error::OracleUnavailable as u32));
+        assert!(matches!(results.get(2).unwrap(), LiquidationResult::Liquidated(_)));
+
+        // The failed entry left nothing half-written behind
+        assert_eq!(client.get_position(&stuck, &btc), Some(stuck_position));
+        assert_eq!(client.get_account(&stuck).collateral, stuck_collateral);
+        assert_eq!(env.as_contract(&contract_id, || FlashPerp::market_state(&env, &btc)), btc_market);
+        assert!(client.get_position(&backstop, &btc).is_none());
+        assert!(client.get_position(&first, &xlm).is_none());
+        assert!(client.get_position(&last, &xlm).is_none());
+    }
 
-        // Calculate absolute change in quote ( |Δquote| ) without risking under-/overflow
-        let delta_quote = if reserve.quote >= quote_before {
-            reserve.quote - quote_before
-        } else {
-            quote_before - reserve.quote
-        };
+    #[test]
+    fn test_liquidation_auction() {
+        let env = Env::default();
+        env.mock_all_auths();
+        env.ledger().with_mut(|l| l.timestamp = 1_000);
 
-        // Fee = |Δquote| × feeBp / 10 000 with overflow checking
-        let fee = delta_quote
-            .checked_mul(FEE_BP).ok_or(Error::Overflow)?
-            / 10_000;
+        let contract_id = env.register(FlashPerp, ());
+        let client = FlashPerpClient::new(&env, &contract_id);
 
-        // Pool retains the fee in quote asset – check for overflow
-        reserve.quote = reserve.quote.checked_add(fee).ok_or(Error::Overflow)?;
-        
-        env.storage().persistent().set(&DataKey::Reserves(symbol.clone()), &reserve);
-        env.storage().persistent().extend_ttl(&DataKey::Reserves(symbol.clone()), 10_000, 10_000);
-        
-        Ok(())
-    }
+        let admin = Address::generate(&env);
+        let token = Address::generate(&env);
+        let trader = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-    fn calculate_free_collateral(env: &Env, trader: &Address) -> Result<i128, Error> {
-        let collateral = Self::get_collateral(env, trader);
-        let symbols = vec![&env, symbol_short!("XLM"), symbol_short!("BTC"), symbol_short!("ETH")];
-        
-        let mut total_margin_used = 0i128;
-        
-        for symbol in symbols {
-            let position_key = DataKey::Position( 
-                trader.clone(), 
-                symbol.clone() 
-            );
-            
-            if let Some(position) = env.storage().persistent().get::<DataKey, Position>(&position_key) {
-                total_margin_used += position.margin;
-            }
-        }
-        
-        Ok(collateral - total_margin_used)
+        let _ = client.initialize(&admin, &token);
+        let _ = client.lp_deposit(&admin, &1_000_000_000);
+        let _ = client.deposit_collateral(&trader, &10_000_000);
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &2_000_000, &limit);
+
+        assert!(!client.flag_liquidation(&trader, &symbol));
+        assert_eq!(client.get_liquidation_bonus_bp(&trader, &symbol), MIN_BONUS_BP);
+
+        // Topping up margin resets an auction in progress
+        set_mock_price(&env, &contract_id, &symbol, 85_000);
+        assert!(client.flag_liquidation(&trader, &symbol));
+        env.ledger().with_mut(|l| l.timestamp = 1_600);
+        assert_eq!(client.get_liquidation_bonus_bp(&trader, &symbol), BONUS_BP);
+        let _ = client.add_margin(&trader, &symbol, &10);
+        assert_eq!(client.get_liquidation_bonus_bp(&trader, &symbol), MIN_BONUS_BP);
+
+        // So does a keeper re-check that finds the position healthy again
+        assert!(client.flag_liquidation(&trader, &symbol));
+        set_mock_price(&env, &contract_id, &symbol, 100_000);
+        let results = client.liquidate_batch(&liquidator, &vec![&env, (trader.clone(), symbol.clone())]);
+        assert_eq!(results, vec![&env, LiquidationResult::Healthy]);
+        set_mock_price(&env, &contract_id, &symbol, 85_000);
+        assert_eq!(client.get_liquidation_bonus_bp(&trader, &symbol), MIN_BONUS_BP);
+
+        // Halfway through the ramp the bonus is halfway between min and max
+        assert!(client.flag_liquidation(&trader, &symbol));
+        env.ledger().with_mut(|l| l.timestamp = 1_900);
+        let bonus_bp = MIN_BONUS_BP + (BONUS_BP - MIN_BONUS_BP) / 2;
+        assert_eq!(client.get_liquidation_bonus_bp(&trader, &symbol), bonus_bp);
+
+        let notional = (100_000_000 * client.get_mark_price_view(&symbol)) / DEC_P;
+        let _ = client.liquidate(&liquidator, &trader, &symbol);
+        assert_eq!(client.get_account(&liquidator).collateral, notional * bonus_bp / 10_000);
+
+        let inverted = LiquidationAuction { min_bonus_bp: 300, max_bonus_bp: 200, ramp_seconds: 60 };
+        let res = client.try_queue_change(&admin, &ParamChange::LiquidationAuction(inverted));
+        assert_eq!(res, Err(Ok(Error::InvalidAmount)));
+        let auction = LiquidationAuction { min_bonus_bp: 50, max_bonus_bp: 300, ramp_seconds: 60 };
+        apply_change(&env, &client, &admin, ParamChange::LiquidationAuction(auction));
+        assert_eq!(client.get_liquidation_auction().max_bonus_bp, 300);
     }
 
-    fn calculate_margin_ratio(position: &Position, mark_price: i128) -> i128 {
-        let current_notional = (position.size.abs() * mark_price) / DEC_P;
-        if current_notional == 0 {
-            return 10_000; // 100%
-        }
-        (position.margin * 10_000) / current_notional
-    }
+    #[test]
+    fn test_backstop_takeover() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let backstop = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-    fn calculate_funding_payment(position: &Position, funding: &FundingData) -> i128 {
-        let funding_diff = funding.rate - position.funding_index;
-        (position.size * funding_diff) / DEC_F
-    }
+        let _ = client.deposit_collateral(&trader, &10_000_000);
+        let _ = client.deposit_collateral(&backstop, &5_000_000);
+        let _ = client.set_backstop(&admin, &Some(backstop.clone()));
+        assert_eq!(client.get_backstop(), Some(backstop.clone()));
 
-    // Mock prices for testing - remove when using real oracle
-    #[cfg(test)]
-    fn _mock_oracle_price(symbol: Symbol) -> Result<i128, Error> {
-        match symbol {
-            s if s == symbol_short!("XLM") => Ok(100_000), // $0.10
-            s if s == symbol_short!("BTC") => Ok(100_000_000_000), // $100,000
-            s if s == symbol_short!("ETH") => Ok(4_000_000_000), // $4,000
-            _ => Err(Error::InvalidSymbol),
-        }
-    }
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let fee = client.quote_open(&trader, &symbol, &100_000_000, &2_000_000).fee;
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &2_000_000, &limit);
 
-    #[cfg(not(test))]
-    fn _mock_oracle_price(_symbol: Symbol) -> Result<i128, Error> {
-        Err(Error::OracleUnavailable)
+        set_mock_price(&env, &contract_id, &symbol, 85_000);
+        let mark = client.get_mark_price_view(&symbol);
+        let view = client.get_account(&trader).positions.get(0).unwrap();
+        let bonus = (view.size * mark / DEC_P) * MIN_BONUS_BP / 10_000;
+        let remaining = view.margin + view.unrealized_pnl - view.accrued_funding - bonus;
+
+        let _ = client.liquidate(&liquidator, &trader, &symbol);
+        let topics: Vec<Val> = (symbol_short!("TAKEOVER"), trader.clone(), symbol.clone()).into_val(&env);
+        let (_, _, data) = env.events().all().iter().find(|(_, t, _)| *t == topics).unwrap();
+        let takeover = TakeoverEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((takeover.backstop, takeover.remaining_margin), (backstop.clone(), remaining));
+
+        // The backstop now holds the position at the mark; the AMM was not touched
+        assert!(client.get_position(&trader, &symbol).is_none());
+        assert_eq!(client.get_mark_price_view(&symbol), mark);
+        let inherited = client.get_position(&backstop, &symbol).unwrap();
+        assert_eq!(inherited.size, 100_000_000);
+        assert_eq!(inherited.notional, 100_000_000 * mark / DEC_P);
+        assert_eq!(inherited.margin, inherited.notional * IMR_BP / 10_000);
+        assert_eq!(takeover.position, inherited);
+
+        // Trader forfeits the whole position margin, the backstop receives what was left of it
+        assert_eq!(client.get_account(&trader).collateral, 8_000_000 - fee);
+        assert_eq!(client.get_account(&backstop).collateral, 5_000_000 + remaining);
+
+        // A takeover that would push the backstop past the position cap goes to the AMM
+        let second = Address::generate(&env);
+        let _ = client.deposit_collateral(&second, &10_000_000);
+        let _ = client.open_position(&second, &symbol, &100_000_000, &2_000_000, &limit);
+        let limits = MarketLimits { max_long_oi: i128::MAX, max_short_oi: i128::MAX, max_position_size: 150_000_000 };
+        apply_change(&env, &client, &admin, ParamChange::MarketLimits(symbol.clone(), limits));
+        set_mock_price(&env, &contract_id, &symbol, 70_000);
+        let _ = client.liquidate(&liquidator, &second, &symbol);
+        let (_, _, data) = env.events().all().last().unwrap();
+        assert_eq!(LiquidateEvent::try_from_val(&env, &data).unwrap().backstop, None);
+        assert!(client.get_position(&second, &symbol).is_none());
+        assert_eq!(client.get_position(&backstop, &symbol).unwrap().size, 100_000_000);
+
+        // Backstop mode can be switched off again
+        let _ = client.set_backstop(&admin, &None);
+        assert_eq!(client.get_backstop(), None);
     }
 
-    // ---------------- Permissionless funding keeper ----------------
-    pub fn poke_funding(env: Env, symbol: Symbol) -> Result<(), Error> {
-        Self::check_not_paused(&env)?;
-        Self::validate_symbol(&symbol)?;
-
-        const FUNDING_PERIOD: u64 = 1800; // 30 minutes
-        const MAX_FUNDING_VEL_BP: i128 = 1_000; // 10% per day
+    #[test]
+    fn test_lp_vault() {
+        let env = Env::default();
+        env.mock_all_auths();
+        env.ledger().with_mut(|l| l.timestamp = 1_000);
 
-        let mut funding = Self::get_funding_data(&env, &symbol);
-        let now = env.ledger().timestamp();
-        if now - funding.last_update < FUNDING_PERIOD {
-            return Ok(()); // ignore early calls
-        }
+        let contract_id = env.register(FlashPerp, ());
+        let client = FlashPerpClient::new(&env, &contract_id);
 
-        #[cfg(test)]
-        let oracle_price = Self::_mock_oracle_price(symbol.clone())?;
+        let admin = Address::generate(&env);
+        let token = Address::generate(&env);
+        let lp = Address::generate(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        #[cfg(not(test))]
-        let oracle_price = fetch_oracle_price(&env, symbol.clone())?;
+        let _ = client.initialize(&admin, &token);
+        let _ = client.deposit_collateral(&trader, &100_000_000);
+
+        // Without liquidity the vault cannot take the other side
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let res = client.try_open_position(&trader, &symbol, &100_000_000, &3_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::UtilizationExceeded)));
+
+        assert_eq!(client.lp_deposit(&lp, &20_000_000), 20_000_000);
+        assert_eq!(client.get_vault_view().share_price, DEC_P);
+
+        // $10 of skew fits under 80% of a $20 pool, another $10 does not
+        let fee = client.quote_open(&trader, &symbol, &100_000_000, &3_000_000).fee;
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &3_000_000, &limit);
+        let res = client.try_open_position(&trader, &symbol, &100_000_000, &3_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::UtilizationExceeded)));
+
+        // Trader profit is the vault's loss, marked into NAV before it is realised
+        set_mock_price(&env, &contract_id, &symbol, 110_000);
+        let view = client.get_vault_view();
+        let trader_pnl = client.get_account(&trader).positions.get(0).unwrap().unrealized_pnl;
+        assert_eq!(view.balance, 20_000_000 + fee);
+        assert_eq!(view.nav, view.balance - trader_pnl);
+        assert!(view.share_price < DEC_P);
+
+        let close_quote = client.quote_close(&trader, &symbol, &100_000_000);
+        let _ = client.close_position(&trader, &symbol, &100_000_000, &0);
+        let view = client.get_vault_view();
+        assert_eq!(view.balance, 20_000_000 + fee + close_quote.fee - close_quote.realized_pnl);
+        assert_eq!(view.nav, view.balance);
+
+        // Withdrawals wait out both cooldowns and pay at the current share
+        // price, except that trader losses not yet realised are left out
+        let _ = client.open_position(&trader, &symbol, &50_000_000, &3_000_000, &limit);
+        set_mock_price(&env, &contract_id, &symbol, 95_000);
+        let res = client.try_lp_request_withdraw(&lp, &10_000_000);
+        assert_eq!(res, Err(Ok(Error::CooldownActive)));
+        env.ledger().with_mut(|l| l.timestamp += LP_DEPOSIT_COOLDOWN);
+        let _ = client.lp_request_withdraw(&lp, &10_000_000);
+        assert_eq!(client.try_lp_withdraw(&lp), Err(Ok(Error::CooldownActive)));
+        env.ledger().with_mut(|l| l.timestamp += LP_WITHDRAW_COOLDOWN);
+
+        let view = client.get_vault_view();
+        assert!(view.nav > view.balance);
+        let paid = client.lp_withdraw(&lp);
+        assert_eq!(paid, view.balance / 2);
+        assert_eq!(client.get_lp_position(&lp).shares, 10_000_000);
+        assert_eq!(client.get_vault_view().total_shares, 10_000_000);
+        let _ = client.close_position(&trader, &symbol, &50_000_000, &0);
+        let view = client.get_vault_view();
+        assert_eq!(view.nav, view.balance);
+
+        // The last LP can leave while the book is balanced
+        let other = Address::generate(&env);
+        let _ = client.deposit_collateral(&other, &100_000_000);
+        let _ = client.open_position(&trader, &symbol, &50_000_000, &3_000_000, &(limit * 2));
+        let _ = client.open_position(&other, &symbol, &-50_000_000, &3_000_000, &0);
+        assert_eq!(client.get_vault_view().exposure, 0);
+        let _ = client.lp_request_withdraw(&lp, &10_000_000);
+        env.ledger().with_mut(|l| l.timestamp += LP_WITHDRAW_COOLDOWN);
+        let _ = client.lp_withdraw(&lp);
+        assert_eq!(client.get_vault_view().total_shares, 0);
+
+        // Nobody may buy into a vault that owes traders more than it holds
+        let _ = client.close_position(&other, &symbol, &-50_000_000, &(limit * 2));
+        set_mock_price(&env, &contract_id, &symbol, 110_000);
+        let newcomer = Address::generate(&env);
+        assert!(client.get_vault_view().nav < 0);
+        let res = client.try_lp_deposit(&newcomer, &20_000_000);
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+
+        // Once every LP is out, value the vault still earns does not go to the
+        // next depositor: it stays behind shares nobody holds
+        set_mock_price(&env, &contract_id, &symbol, 90_000);
+        let _ = client.close_position(&trader, &symbol, &50_000_000, &0);
+        let nav = client.get_vault_view().nav;
+        assert!(nav > 0);
+        assert_eq!(client.lp_deposit(&newcomer, &20_000_000), 20_000_000);
+        let view = client.get_vault_view();
+        assert_eq!((view.total_shares, view.share_price), (nav + 20_000_000, DEC_P));
+    }
 
-        // Reuse oracle_price to compute mark price without another oracle call
-        let net_oi: i128 = env.storage().persistent()
-            .get::<DataKey, i128>(&DataKey::NetOi(symbol.clone()))
-            .unwrap_or(0);
-        let skew_scale: i128 = env.storage().persistent()
-            .get::<DataKey, i128>(&DataKey::SkewScale(symbol.clone()))
-            .unwrap_or(1);
+    #[test]
+    fn test_open_interest_caps() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let carol = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        let limit_oi = (skew_scale * MAX_DRIFT_BP) / 10_000;
-        let clamped_oi = net_oi.max(-limit_oi).min(limit_oi);
-        let pd_bp = (clamped_oi * 10_000) / skew_scale;
-        let adj_bp = pd_bp.max(-MAX_DRIFT_BP).min(MAX_DRIFT_BP);
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+        let _ = client.deposit_collateral(&carol, &100_000_000);
+
+        let zero = MarketLimits { max_long_oi: 0, max_short_oi: 1, max_position_size: 1 };
+        let res = client.try_queue_change(&admin, &ParamChange::MarketLimits(symbol.clone(), zero));
+        assert_eq!(res, Err(Ok(Error::InvalidAmount)));
+        let limits = MarketLimits { max_long_oi: 150_000_000, max_short_oi: 100_000_000, max_position_size: 120_000_000 };
+        apply_change(&env, &client, &admin, ParamChange::MarketLimits(symbol.clone(), limits));
+        // Restart the funding clock after the timelock wait (no skew yet, so no accrual)
+        let _ = client.poke_funding(&symbol);
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &3_000_000, &limit);
+        let _ = client.open_position(&bob, &symbol, &-80_000_000, &3_000_000, &0);
+
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.long_oi, stats.short_oi, stats.net_oi), (100_000_000, 80_000_000, 20_000_000));
+        assert_eq!(stats.mark_price, client.get_mark_price_view(&symbol));
+        assert_eq!(stats.long_oi_value, 100_000_000 * stats.mark_price / DEC_P);
+        assert_eq!(stats.short_oi_value, 80_000_000 * stats.mark_price / DEC_P);
+        // 20 XLM net long against a 10 000 XLM skew scale trades 20 bp rich,
+        // which drives funding at 20 / 100 of the max 10%/day velocity
+        assert_eq!(stats.skew_bp, 20);
+        assert_eq!(stats.funding_velocity, 200);
+        let index_before = stats.funding_index;
+        env.ledger().with_mut(|l| l.timestamp += FUNDING_PERIOD);
+        let _ = client.poke_funding(&symbol);
+        assert_eq!(
+            client.get_market_stats(&symbol).funding_index - index_before,
+            200 * FUNDING_PERIOD as i128 / 86_400
+        );
 
-        let mark_price = (oracle_price * (10_000 + adj_bp)) / 10_000;
+        // Per-account cap, then the market-wide long and short caps
+        let res = client.try_open_position(&alice, &symbol, &30_000_000, &2_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::OpenInterestCap)));
+        let res = client.try_open_position(&carol, &symbol, &60_000_000, &2_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::OpenInterestCap)));
+        let res = client.try_open_position(&carol, &symbol, &-30_000_000, &2_000_000, &0);
+        assert_eq!(res, Err(Ok(Error::OpenInterestCap)));
+        let _ = client.open_position(&carol, &symbol, &-20_000_000, &2_000_000, &0);
+
+        // Closing and liquidation release their side of the book
+        let _ = client.close_position(&carol, &symbol, &-20_000_000, &limit);
+        set_mock_price(&env, &contract_id, &symbol, 50_000);
+        let _ = client.liquidate(&liquidator, &alice, &symbol);
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.long_oi, stats.short_oi, stats.net_oi), (0, 80_000_000, -80_000_000));
+    }
 
-        let premium_bp = ((mark_price - oracle_price) * 10_000) / oracle_price;
-        // funding velocity proportional to premium, clamped to max drift
-        let capped_premium_bp = premium_bp.max(-MAX_DRIFT_BP).min(MAX_DRIFT_BP);
+    #[test]
+    fn test_margin_tiers() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        // Δrate = premium * maxVel * elapsed / (maxDrift * secondsPerDay)
-        let elapsed: i128 = (now - funding.last_update) as i128;
-        let numerator = capped_premium_bp
-            .checked_mul(MAX_FUNDING_VEL_BP).ok_or(Error::Overflow)?
-            .checked_mul(elapsed).ok_or(Error::Overflow)?;
-        let denominator: i128 = MAX_DRIFT_BP * 86_400;
-        let delta_rate = numerator / denominator;
+        let _ = client.deposit_collateral(&alice, &100_000_000);
 
-        funding.rate += delta_rate;
-        funding.last_update = now;
+        let flat = client.get_margin_tiers(&symbol);
+        assert_eq!(flat, vec![&env, MarginTier { min_notional: 0, imr_bp: IMR_BP, mmr_bp: MMR_BP }]);
 
-        env.storage().persistent().set(&DataKey::Funding(symbol.clone()), &funding);
-        env.storage().persistent().extend_ttl(&DataKey::Funding(symbol.clone()), 10_000, 10_000);
+        let bad_start = vec![&env, MarginTier { min_notional: 1, imr_bp: 2_000, mmr_bp: 1_000 }];
+        let res = client.try_queue_change(&admin, &ParamChange::MarginTiers(symbol.clone(), bad_start));
+        assert_eq!(res, Err(Ok(Error::InvalidAmount)));
+        let decreasing = vec![
+            &env,
+            MarginTier { min_notional: 0, imr_bp: 2_000, mmr_bp: 1_000 },
+            MarginTier { min_notional: 5_000_000, imr_bp: 1_500, mmr_bp: 1_000 },
+        ];
+        let res = client.try_queue_change(&admin, &ParamChange::MarginTiers(symbol.clone(), decreasing));
+        assert_eq!(res, Err(Ok(Error::InvalidAmount)));
+
+        // Positions of 5 USDC notional and above need 50% initial / 25% maintenance
+        let tiers = vec![
+            &env,
+            MarginTier { min_notional: 0, imr_bp: 2_000, mmr_bp: 1_000 },
+            MarginTier { min_notional: 5_000_000, imr_bp: 5_000, mmr_bp: 2_500 },
+        ];
+        apply_change(&env, &client, &admin, ParamChange::MarginTiers(symbol.clone(), tiers.clone()));
+        assert_eq!(client.get_margin_tiers(&symbol), tiers);
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &40_000_000, &1_000_000, &limit);
+        assert_eq!(client.get_account(&alice).positions.get(0).unwrap().imr_bp, 2_000);
+
+        // Growing into the upper tier re-margins the existing 4 USDC at 50% too
+        let quote = client.quote_open(&alice, &symbol, &60_000_000, &10_000_000);
+        let trade_notional = 60_000_000 * quote.fill_price / DEC_P;
+        assert!(quote.required_margin >= trade_notional * 5_000 / 10_000 + 4_000_000 * 3_000 / 10_000);
+        let res = client.try_open_position(&alice, &symbol, &60_000_000, &(trade_notional * 2_000 / 10_000), &limit);
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+        let _ = client.open_position(&alice, &symbol, &60_000_000, &quote.required_margin, &limit);
+
+        let view = client.get_account(&alice).positions.get(0).unwrap();
+        assert_eq!((view.imr_bp, view.mmr_bp), (5_000, 2_500));
+        assert_eq!(client.try_remove_margin(&alice, &symbol, &1_000_000), Err(Ok(Error::InsufficientCollateral)));
+
+        // The liquidation price uses the 25% MMR; just above it the position is
+        // safe, just below it is liquidatable even though its ratio is far above 10%
+        let liq_mark = view.liquidation_price;
+        set_mock_price(&env, &contract_id, &symbol, (liq_mark + 200) * 100 / 101);
+        assert!(!client.flag_liquidation(&alice, &symbol));
+        set_mock_price(&env, &contract_id, &symbol, (liq_mark - 200) * 100 / 101);
+        let ratio = client.get_account(&alice).positions.get(0).unwrap().margin_ratio;
+        assert!(ratio < 2_500 && ratio > 2_000);
+        assert!(client.flag_liquidation(&alice, &symbol));
+        let _ = client.liquidate(&liquidator, &alice, &symbol);
+        assert!(client.get_position(&alice, &symbol).is_none());
+    }
 
-        env.events().publish((symbol_short!("FUNDING"), symbol), (delta_rate, oracle_price, mark_price));
-        Ok(())
+    #[test]
+    fn test_market_status() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+        let eth = symbol_short!("ETH");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+
+        let xlm_limit = client.get_mark_price_view(&xlm) * 2;
+        let eth_limit = client.get_mark_price_view(&eth) * 2;
+        let _ = client.open_position(&alice, &xlm, &100_000_000, &2_100_000, &xlm_limit);
+        let _ = client.open_position(&bob, &xlm, &100_000_000, &5_000_000, &xlm_limit);
+
+        let res = client.try_set_market_status(&alice, &xlm, &MarketStatus::ReduceOnly);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+
+        // Reduce-only XLM: no new exposure there, ETH unaffected
+        let _ = client.set_market_status(&admin, &xlm, &MarketStatus::ReduceOnly);
+        assert_eq!(client.get_market_stats(&xlm).status, MarketStatus::ReduceOnly);
+        let res = client.try_open_position(&alice, &xlm, &10_000_000, &1_000_000, &xlm_limit);
+        assert_eq!(res, Err(Ok(Error::ReduceOnly)));
+        assert_eq!(client.try_remove_margin(&bob, &xlm, &100_000), Err(Ok(Error::ReduceOnly)));
+        let _ = client.add_margin(&bob, &xlm, &100_000);
+        let _ = client.open_position(&alice, &eth, &10_000, &10_000_000, &eth_limit);
+
+        // ... while losing positions can still be closed and liquidated
+        set_mock_price(&env, &contract_id, &xlm, 85_000);
+        let _ = client.liquidate(&liquidator, &alice, &xlm);
+        let _ = client.close_position(&bob, &xlm, &50_000_000, &0);
+
+        // Halting XLM freezes it, and batch entries on it fail individually
+        let _ = client.set_market_status(&admin, &xlm, &MarketStatus::Halted);
+        assert_eq!(client.try_close_position(&bob, &xlm, &50_000_000, &0), Err(Ok(Error::Paused)));
+        let results = client.liquidate_batch(&liquidator, &vec![&env, (bob.clone(), xlm.clone())]);
+        assert_eq!(results, vec![&env, LiquidationResult::Failed(Error::Paused as u32)]);
+        let _ = client.set_market_status(&admin, &xlm, &MarketStatus::Active);
+
+        // Per-operation flags
+        let _ = client.set_operation_paused(&admin, &Operation::Withdraw, &true);
+        assert!(client.is_operation_paused(&Operation::Withdraw));
+        assert_eq!(client.try_withdraw_collateral(&bob, &1_000_000), Err(Ok(Error::Paused)));
+        let _ = client.close_position(&bob, &xlm, &50_000_000, &0);
+        let _ = client.set_operation_paused(&admin, &Operation::Withdraw, &false);
+        let _ = client.withdraw_collateral(&bob, &1_000_000);
+
+        // Global status applies on top of every market
+        let _ = client.pause(&admin);
+        assert_eq!(client.get_market_status(&eth), MarketStatus::Halted);
+        assert_eq!(client.try_withdraw_collateral(&bob, &1_000_000), Err(Ok(Error::Paused)));
+        let _ = client.set_global_status(&admin, &MarketStatus::ReduceOnly);
+        assert_eq!(client.get_global_status_view(), MarketStatus::ReduceOnly);
+        let _ = client.withdraw_collateral(&bob, &1_000_000);
+        let res = client.try_open_position(&bob, &eth, &10_000, &10_000_000, &eth_limit);
+        assert_eq!(res, Err(Ok(Error::ReduceOnly)));
+        let _ = client.close_position(&alice, &eth, &10_000, &0);
+        let _ = client.unpause(&admin);
+        assert_eq!(client.get_market_status(&eth), MarketStatus::Active);
     }
-}
 
-// Tests
-#[cfg(test)]
-mod test {
-    use super::*;
-    use soroban_sdk::testutils::Address as _;
+    #[test]
+    fn test_settle_market() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let liquidator = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &limit);
+        let _ = client.open_position(&bob, &symbol, &-50_000_000, &2_100_000, &0);
+
+        // The final price must come from the admin and stay near the oracle
+        assert_eq!(client.try_settle_market(&alice, &symbol, &102_000), Err(Ok(Error::Unauthorized)));
+        assert_eq!(client.try_settle_market(&admin, &symbol, &103_000), Err(Ok(Error::PriceOutOfBounds)));
+        let _ = client.settle_market(&admin, &symbol, &102_000);
+        assert_eq!(client.try_settle_market(&admin, &symbol, &102_000), Err(Ok(Error::MarketSettled)));
+        assert_eq!(client.get_settlement_view(&symbol).unwrap().price, 102_000);
+
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.status, stats.mark_price), (MarketStatus::Halted, 102_000));
+        let res = client.try_open_position(&alice, &symbol, &10_000_000, &1_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::MarketSettled)));
+        assert_eq!(client.try_close_position(&alice, &symbol, &100_000_000, &0), Err(Ok(Error::MarketSettled)));
+        assert_eq!(client.try_liquidate(&liquidator, &bob, &symbol), Err(Ok(Error::MarketSettled)));
+        assert_eq!(client.try_poke_funding(&symbol), Err(Ok(Error::MarketSettled)));
+
+        // Claims pay the settlement-price PnL net of funding, whatever the oracle does next
+        set_mock_price(&env, &contract_id, &symbol, 50_000);
+        let view = client.get_account(&alice).positions.get(0).unwrap();
+        let vault_before = client.get_vault_view().balance;
+        let collateral_before = client.get_account(&alice).collateral;
+        let credited = client.claim_settlement(&alice, &symbol);
+        assert_eq!(credited, view.unrealized_pnl - view.accrued_funding);
+        assert_eq!(client.get_account(&alice).collateral, collateral_before + credited);
+        let bob_credited = client.claim_settlement(&bob, &symbol);
+        assert!(bob_credited < 0);
+        assert_eq!(client.get_vault_view().balance, vault_before - credited - bob_credited);
+
+        assert_eq!(client.try_claim_settlement(&alice, &symbol), Err(Ok(Error::PositionNotFound)));
+        let eth = symbol_short!("ETH");
+        assert_eq!(client.try_claim_settlement(&alice, &eth), Err(Ok(Error::MarketNotSettled)));
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.long_oi, stats.short_oi, stats.net_oi), (0, 0, 0));
+    }
 
     #[test]
-    fn test_initialization() {
+    fn test_shutdown() {
         let env = Env::default();
+        env.mock_all_auths();
+
         let contract_id = env.register(FlashPerp, ());
         let client = FlashPerpClient::new(&env, &contract_id);
 
         let admin = Address::generate(&env);
-        let token = Address::generate(&env);
-        let result = client.initialize(&admin, &token);
-        assert_eq!(result, ());
+        let token = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        // Test double initialization
-        let result2 = client.try_initialize(&admin, &token);
-        assert_eq!(result2, Err(Ok(Error::AlreadyInitialized)));
+        let _ = client.initialize(&admin, &token);
+        let _ = client.lp_deposit(&admin, &1_000_000_000);
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &limit);
+        let _ = client.open_position(&bob, &symbol, &-50_000_000, &2_100_000, &0);
+
+        assert_eq!(client.try_claim_shutdown(&alice), Err(Ok(Error::NotShutDown)));
+        assert_eq!(client.try_shutdown(&alice, &Map::new(&env)), Err(Ok(Error::Unauthorized)));
+
+        // The contract holds half of what it owes
+        set_mock_price(&env, &contract_id, &symbol, 105_000);
+        StellarAssetClient::new(&env, &token).mint(&contract_id, &600_000_000);
+
+        // A dead feed needs a fallback price; live feeds ignore theirs
+        let eth = symbol_short!("ETH");
+        set_mock_price(&env, &contract_id, &eth, 0);
+        assert_eq!(client.try_shutdown(&admin, &Map::new(&env)), Err(Ok(Error::OracleUnavailable)));
+        let fallback = map![&env, (eth.clone(), 3_900_000_000), (symbol.clone(), 1)];
+        let _ = client.shutdown(&admin, &fallback);
+        assert_eq!(client.try_shutdown(&admin, &fallback), Err(Ok(Error::ShutDown)));
+
+        let state = client.get_shutdown().unwrap();
+        assert_eq!(state.assets, 600_000_000);
+        assert_eq!(state.prices.get(symbol.clone()).unwrap(), client.get_mark_price_view(&symbol));
+        assert_eq!(state.prices.get(eth).unwrap(), 3_900_000_000);
+        let res = client.try_open_position(&bob, &symbol, &10_000_000, &2_000_000, &limit);
+        assert_eq!(res, Err(Ok(Error::ShutDown)));
+        assert_eq!(client.try_deposit_collateral(&bob, &1_000_000), Err(Ok(Error::ShutDown)));
+        assert_eq!(client.try_withdraw_collateral(&bob, &1_000_000), Err(Ok(Error::ShutDown)));
+
+        // Later price moves do not change the snapshotted equity
+        set_mock_price(&env, &contract_id, &symbol, 50_000);
+        let equity = client.get_shutdown_equity(&alice);
+        assert!(equity > 100_000_000);
+        let bob_equity = client.get_shutdown_equity(&bob);
+
+        // Nothing is paid until every account is tallied: bob's short is still open
+        assert_eq!(client.tally_shutdown(&vec![&env, alice.clone()]), 1);
+        assert_eq!(client.try_claim_shutdown(&alice), Err(Ok(Error::TallyPending)));
+        assert_eq!(client.try_lp_claim_shutdown(&admin), Err(Ok(Error::TallyPending)));
+        let account = client.get_account(&alice);
+        assert_eq!((account.collateral, account.positions.len()), (0, 0));
+        assert_eq!(client.get_shutdown_equity(&alice), equity);
+        assert_eq!(client.tally_shutdown(&vec![&env, alice.clone(), bob.clone()]), 1);
+
+        // LPs get what is left of the book, and every claim takes the same haircut
+        let state = client.get_shutdown().unwrap();
+        assert!(state.tallied);
+        assert_eq!(state.trader_claims, equity + bob_equity);
+        assert_eq!(state.vault_nav, state.book - state.trader_claims);
+        assert_eq!(state.total_claims, state.book);
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.long_oi, stats.short_oi, stats.net_oi), (0, 0, 0));
+
+        let lp_shares = client.get_lp_position(&admin).shares;
+        let lp_paid = client.lp_claim_shutdown(&admin);
+        assert_eq!(lp_paid, lp_shares * state.vault_nav / state.total_shares * state.assets / state.total_claims);
+        assert_eq!(client.try_lp_claim_shutdown(&admin), Err(Ok(Error::InvalidAmount)));
+        let paid = client.claim_shutdown(&alice);
+        assert_eq!(paid, equity * state.assets / state.total_claims);
+        assert_eq!(client.try_claim_shutdown(&alice), Err(Ok(Error::InvalidAmount)));
+        let bob_paid = client.claim_shutdown(&bob);
+        assert_eq!(bob_paid, bob_equity * state.assets / state.total_claims);
+
+        assert_eq!(client.get_shutdown().unwrap().paid, paid + bob_paid + lp_paid);
+        assert!(paid + bob_paid + lp_paid <= state.assets);
+        assert!(state.assets - (paid + bob_paid + lp_paid) < 3);
     }
 
     #[test]
-    fn test_deposit_withdraw() {
+    fn test_shutdown_token_debt() {
         let env = Env::default();
         env.mock_all_auths();
 
@@ -803,63 +5426,472 @@ mod test {
         let client = FlashPerpClient::new(&env, &contract_id);
 
         let admin = Address::generate(&env);
-        let token = Address::generate(&env);
-        let trader = Address::generate(&env);
+        let token = env.register_stellar_asset_contract_v2(admin.clone()).address();
+        let xlm_token = Address::generate(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let btc = symbol_short!("BTC");
 
         let _ = client.initialize(&admin, &token);
+        let _ = client.lp_deposit(&admin, &1_000_000_000);
+        StellarAssetClient::new(&env, &token).mint(&contract_id, &1_000_000_000);
+        let asset = CollateralAsset { asset: symbol_short!("XLM"), decimals: 7, haircut_bp: 2_000, deposit_cap: 100_000_000_000 };
+        apply_change(&env, &client, &admin, ParamChange::CollateralAsset(xlm_token.clone(), asset));
+        let _ = client.deposit_token_collateral(&alice, &xlm_token, &10_000_000_000);
+        let _ = client.deposit_token_collateral(&bob, &xlm_token, &10_000_000_000);
+
+        // Alice's isolated loss exceeds her USD collateral, which is only the fee debt
+        let mark = client.get_mark_price_view(&btc);
+        let size = 100_000_000 * DEC_P / mark;
+        let _ = client.open_position(&alice, &btc, &size, &21_000_000, &(mark * 2));
+        let debt = 21_000_000 - client.get_account(&alice).collateral;
+        set_mock_price(&env, &contract_id, &btc, 60_000_000_000);
+        let _ = client.shutdown(&admin, &Map::new(&env));
+
+        // Her tokens cover the shortfall at their haircut value before the rest is released
+        assert_eq!(client.get_shutdown_equity(&alice), 0);
+        assert_eq!(client.tally_shutdown(&vec![&env, alice.clone()]), 1);
+        let per_unit = 100_000 * (10_000 - 2_000);
+        let seized = (debt * 10_000_000 * 10_000 + per_unit - 1) / per_unit;
+        assert_eq!(client.get_treasury().get(xlm_token.clone()), Some(seized));
+        assert!(client.get_token_collateral(&alice).is_empty());
+
+        // A solvent holder gets every token back
+        assert_eq!(client.tally_shutdown(&vec![&env, bob.clone()]), 1);
+        assert!(client.get_token_collateral(&bob).is_empty());
+        assert_eq!(client.get_treasury().get(xlm_token.clone()), Some(seized));
+        env.as_contract(&contract_id, || {
+            let deposits: i128 = env.storage().instance().get(&DataKey::CollateralDeposits(xlm_token.clone())).unwrap();
+            assert_eq!(deposits, 0);
+        });
+
+        // The seized tokens went to the treasury, so LPs only get the book:
+        // her loss past the fee debt never reached the vault NAV
+        let state = client.get_shutdown().unwrap();
+        assert!(state.tallied);
+        assert_eq!((state.trader_claims, state.vault_nav), (0, 1_000_000_000));
+        let alice_paid = client.claim_shutdown(&alice);
+        let bob_paid = client.claim_shutdown(&bob);
+        let lp_paid = client.lp_claim_shutdown(&admin);
+        assert_eq!((alice_paid, bob_paid), (0, 0));
+        assert!(alice_paid + bob_paid + lp_paid <= state.assets);
+
+        // Only the treasurer moves seized tokens out
+        let treasurer = Address::generate(&env);
+        let res = client.try_withdraw_treasury(&treasurer, &xlm_token, &treasurer, &seized);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        let _ = client.grant_role(&admin, &Role::Treasurer, &treasurer);
+        let res = client.try_withdraw_treasury(&treasurer, &xlm_token, &treasurer, &(seized + 1));
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+        let _ = client.withdraw_treasury(&treasurer, &xlm_token, &treasurer, &seized);
+        assert_eq!(client.get_treasury().get(xlm_token.clone()), Some(0));
+    }
 
-        // Deposit
-        let _ = client.deposit_collateral(&trader, &1_000_000_000);
-        assert_eq!(client.get_free_collateral(&trader), 1_000_000_000);
+    #[test]
+    fn test_roles() {
+        let env = Env::default();
+        let (_, client, admin, _) = setup(&env);
+        let guardian = Address::generate(&env);
+        let risk = Address::generate(&env);
+        let oracle_manager = Address::generate(&env);
+        let oracle = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        // Withdraw
-        let _ = client.withdraw_collateral(&trader, &300_000_000);
-        assert_eq!(client.get_free_collateral(&trader), 700_000_000);
+        // Only the owner manages roles, and ownership itself is not grantable
+        let res = client.try_grant_role(&guardian, &Role::Guardian, &guardian);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        assert_eq!(client.try_grant_role(&admin, &Role::Owner, &guardian), Err(Ok(Error::InvalidRole)));
+        let _ = client.grant_role(&admin, &Role::Guardian, &guardian);
+        let _ = client.grant_role(&admin, &Role::RiskManager, &risk);
+        let _ = client.grant_role(&admin, &Role::OracleManager, &oracle_manager);
+        assert!(client.has_role(&Role::Guardian, &guardian));
+        assert!(client.has_role(&Role::RiskManager, &admin));
+        assert!(!client.has_role(&Role::RiskManager, &guardian));
+        assert_eq!(client.get_role_members(&Role::Guardian), vec![&env, guardian.clone()]);
+        assert_eq!(client.get_role_members(&Role::Owner), vec![&env, admin.clone()]);
+
+        // The guardian can only tighten
+        let _ = client.pause(&guardian);
+        assert_eq!(client.try_unpause(&guardian), Err(Ok(Error::Unauthorized)));
+        let _ = client.unpause(&admin);
+        let _ = client.set_market_status(&guardian, &symbol, &MarketStatus::Halted);
+        let res = client.try_set_market_status(&guardian, &symbol, &MarketStatus::ReduceOnly);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        let _ = client.set_market_status(&admin, &symbol, &MarketStatus::Active);
+        let _ = client.set_operation_paused(&guardian, &Operation::Open, &true);
+        let res = client.try_set_operation_paused(&guardian, &Operation::Open, &false);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        let limits = ParamChange::MarketLimits(
+            symbol.clone(),
+            MarketLimits { max_long_oi: 1_000, max_short_oi: 1_000, max_position_size: 1_000 },
+        );
+        assert_eq!(client.try_queue_change(&guardian, &limits), Err(Ok(Error::Unauthorized)));
+
+        // Risk parameters and the oracle have their own managers
+        let _ = client.queue_change(&risk, &limits);
+        assert_eq!(client.try_pause(&risk), Err(Ok(Error::Unauthorized)));
+        let new_oracle = ParamChange::Oracle(oracle.clone());
+        assert_eq!(client.try_queue_change(&risk, &new_oracle), Err(Ok(Error::Unauthorized)));
+        apply_change(&env, &client, &oracle_manager, new_oracle);
+        assert_eq!(client.get_oracle(), oracle);
+
+        let _ = client.revoke_role(&admin, &Role::RiskManager, &risk);
+        assert!(!client.has_role(&Role::RiskManager, &risk));
+        assert_eq!(client.try_queue_change(&risk, &limits), Err(Ok(Error::Unauthorized)));
     }
 
     #[test]
-    fn test_open_close_position() {
+    fn test_admin_transfer() {
         let env = Env::default();
-        env.mock_all_auths();
-
-        let contract_id = env.register(FlashPerp, ());
-        let client = FlashPerpClient::new(&env, &contract_id);
+        let (_, client, admin, _) = setup(&env);
+        let typo = Address::generate(&env);
+        let new_admin = Address::generate(&env);
+
+        assert_eq!(client.try_accept_admin(&new_admin), Err(Ok(Error::NoPendingAdmin)));
+        assert_eq!(client.try_propose_admin(&new_admin, &new_admin), Err(Ok(Error::Unauthorized)));
+
+        // A wrong proposal can be cancelled and never takes effect
+        let _ = client.propose_admin(&admin, &typo);
+        assert_eq!(client.get_pending_admin(), Some(typo.clone()));
+        assert_eq!(client.try_accept_admin(&new_admin), Err(Ok(Error::Unauthorized)));
+        let _ = client.cancel_admin_transfer(&admin);
+        assert_eq!(client.get_pending_admin(), None);
+        assert_eq!(client.try_cancel_admin_transfer(&admin), Err(Ok(Error::NoPendingAdmin)));
+        assert_eq!(client.try_accept_admin(&typo), Err(Ok(Error::NoPendingAdmin)));
+
+        let _ = client.propose_admin(&admin, &new_admin);
+        assert_eq!(client.get_admin_view(), admin);
+        let _ = client.accept_admin(&new_admin);
+        assert_eq!(client.get_admin_view(), new_admin);
+        assert_eq!(client.get_pending_admin(), None);
+        assert!(client.has_role(&Role::RiskManager, &new_admin));
+        assert_eq!(client.try_pause(&admin), Err(Ok(Error::Unauthorized)));
+        let _ = client.pause(&new_admin);
+    }
 
-        let admin = Address::generate(&env);
-        let token = Address::generate(&env);
+    #[test]
+    fn test_timelock() {
+        let env = Env::default();
+        let (_, client, admin, token) = setup(&env);
+        let guardian = Address::generate(&env);
+        let risk = Address::generate(&env);
         let trader = Address::generate(&env);
         let symbol = symbol_short!("XLM");
 
-        let _ = client.initialize(&admin, &token);
-        let _ = client.deposit_collateral(&trader, &10_000_000_000); // 10k USDC
+        let _ = client.deposit_collateral(&trader, &100_000_000);
+        let _ = client.grant_role(&admin, &Role::Guardian, &guardian);
+        let _ = client.grant_role(&admin, &Role::RiskManager, &risk);
+
+        assert_eq!(client.try_queue_change(&risk, &ParamChange::FeeBp(MAX_FEE_BP + 1)), Err(Ok(Error::InvalidAmount)));
+        let res = client.try_queue_change(&risk, &ParamChange::TimelockDelay(0));
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+
+        // Queued changes are public and wait out the delay
+        let id = client.queue_change(&risk, &ParamChange::FeeBp(10));
+        let queued = client.get_queued_changes().get(0).unwrap();
+        assert_eq!((queued.id, queued.proposer.clone()), (id, risk.clone()));
+        assert_eq!(queued.eta, env.ledger().timestamp() + DEFAULT_TIMELOCK_DELAY);
+        assert_eq!(client.try_execute_change(&id), Err(Ok(Error::TimelockActive)));
+        env.ledger().with_mut(|l| l.timestamp = queued.eta - 1);
+        assert_eq!(client.try_execute_change(&id), Err(Ok(Error::TimelockActive)));
+        assert_eq!(client.get_fee_bp(), FEE_BP);
+
+        // The guardian can veto a change before it executes
+        assert_eq!(client.try_cancel_change(&risk, &id), Err(Ok(Error::Unauthorized)));
+        let _ = client.cancel_change(&guardian, &id);
+        assert_eq!(client.get_queued_changes().len(), 0);
+        env.ledger().with_mut(|l| l.timestamp = queued.eta);
+        assert_eq!(client.try_execute_change(&id), Err(Ok(Error::ChangeNotFound)));
+
+        // Executed fee changes apply to the next trade
+        let fee_before = client.quote_open(&trader, &symbol, &100_000_000, &2_100_000).fee;
+        apply_change(&env, &client, &risk, ParamChange::FeeBp(10));
+        assert_eq!(client.get_fee_bp(), 10);
+        let fee_after = client.quote_open(&trader, &symbol, &100_000_000, &2_100_000).fee;
+        assert!((fee_after - 2 * fee_before).abs() <= 1);
+
+        // The delay itself is timelocked, by the current delay
+        apply_change(&env, &client, &admin, ParamChange::TimelockDelay(3_600));
+        assert_eq!(client.get_timelock_delay(), 3_600);
+        let id = client.queue_change(&risk, &ParamChange::FeeBp(5));
+        env.ledger().with_mut(|l| l.timestamp += 3_600);
+        let _ = client.execute_change(&id);
+        assert_eq!(client.get_fee_bp(), 5);
+
+        // The owner sets the protocol's share of trading fees, which the
+        // treasurer withdraws from the treasury instead of the LPs earning it
+        let res = client.try_queue_change(&risk, &ParamChange::ProtocolFeeBp(2_000));
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        assert_eq!(client.try_queue_change(&admin, &ParamChange::ProtocolFeeBp(10_001)), Err(Ok(Error::InvalidAmount)));
+        apply_change(&env, &client, &admin, ParamChange::ProtocolFeeBp(2_000));
+        assert_eq!(client.get_protocol_fee_bp(), 2_000);
+
+        let vault_before = client.get_vault_view().balance;
+        let fee = client.quote_open(&trader, &symbol, &100_000_000, &2_100_000).fee;
+        let _ = client.open_position(&trader, &symbol, &100_000_000, &2_100_000, &(client.get_mark_price_view(&symbol) * 2));
+        let share = fee * 2_000 / 10_000;
+        assert_eq!(client.get_treasury().get(token.clone()), Some(share));
+        assert_eq!(client.get_vault_view().balance, vault_before + fee - share);
+
+        // Only the treasurer moves it out
+        let treasurer = Address::generate(&env);
+        let res = client.try_withdraw_treasury(&treasurer, &token, &treasurer, &share);
+        assert_eq!(res, Err(Ok(Error::Unauthorized)));
+        let _ = client.grant_role(&admin, &Role::Treasurer, &treasurer);
+        let res = client.try_withdraw_treasury(&treasurer, &token, &treasurer, &(share + 1));
+        assert_eq!(res, Err(Ok(Error::InsufficientCollateral)));
+        let _ = client.withdraw_treasury(&treasurer, &token, &treasurer, &share);
+        assert_eq!(client.get_treasury().get(token.clone()), Some(0));
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let carol = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+        let _ = client.deposit_collateral(&carol, &50_000_000);
 
-        // Determine current mark price for slippage limit
         let mark = client.get_mark_price_view(&symbol);
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &(mark * 2));
+        let _ = client.open_position(&bob, &symbol, &-50_000_000, &2_100_000, &0);
+        env.ledger().with_mut(|l| l.timestamp += FUNDING_PERIOD);
+        let _ = client.poke_funding(&symbol);
+
+        let bob_position = client.get_position(&bob, &symbol).unwrap();
+        let funding_index = client.get_market_stats(&symbol).funding_index;
+        let (market, total_collateral) = env.as_contract(&contract_id, || {
+            let market: MarketState = env.storage().persistent().get(&DataKey::Market(symbol.clone())).unwrap();
+            let total: i128 = env.storage().instance().get(&DataKey::TotalCollateral).unwrap();
+            (market, total)
+        });
+
+        store_legacy_state(&env, &contract_id, &[&alice, &bob, &carol]);
+        assert_eq!(client.get_schema_version(), 1);
+
+        // Old entries stay readable, but nothing trades on them before the migration
+        let legacy = client.get_position(&bob, &symbol).unwrap();
+        assert_eq!((legacy.size, legacy.last_update), (bob_position.size, 0));
+        assert_eq!(client.get_account(&bob).margin_used, bob_position.margin);
+        assert_eq!(client.get_market_stats(&symbol).funding_index, funding_index);
+        assert_eq!(client.try_deposit_collateral(&carol, &1), Err(Ok(Error::MigrationPending)));
+
+        // A partial batch leaves the version alone: bob's short is still missing from OI
+        assert_eq!(client.try_migrate(&alice, &vec![&env, alice.clone()]), Err(Ok(Error::Unauthorized)));
+        assert_eq!(client.migrate(&admin, &vec![&env, alice.clone()]), 6);
+        assert_eq!(client.try_complete_migration(&alice), Err(Ok(Error::Unauthorized)));
+        assert_eq!(client.try_complete_migration(&admin), Err(Ok(Error::MigrationPending)));
+        assert_eq!(client.get_schema_version(), 1);
+
+        let traders = vec![&env, alice.clone(), bob.clone(), carol.clone()];
+        assert_eq!(client.migrate(&admin, &traders), 3);
+        client.complete_migration(&admin);
+        assert_eq!(client.get_schema_version(), SCHEMA_VERSION);
+        env.as_contract(&contract_id, || {
+            let storage = env.storage().persistent();
+            let raw: Val = storage.get(&DataKey::Position(bob.clone(), symbol.clone())).unwrap();
+            assert!(Position::try_from_val(&env, &raw).is_ok());
+            let migrated: MarketState = storage.get(&DataKey::Market(symbol.clone())).unwrap();
+            assert_eq!(migrated, market);
+            assert!(!storage.has(&DataKey::Funding(symbol.clone())));
+            let total: i128 = env.storage().instance().get(&DataKey::TotalCollateral).unwrap();
+            assert_eq!(total, total_collateral);
+            assert!(!env.storage().instance().has(&DataKey::Paused));
+            let account: Account = storage.get(&DataKey::Account(bob.clone())).unwrap();
+            assert_eq!(account.markets, vec![&env, symbol.clone()]);
+            assert!(!storage.has(&DataKey::Collateral(bob.clone())));
+        });
+
+        // Idempotent, and migrated positions trade normally down to zero open interest
+        assert_eq!(client.migrate(&admin, &traders), 0);
+        let _ = client.close_position(&alice, &symbol, &100_000_000, &0);
+        let _ = client.close_position(&bob, &symbol, &-50_000_000, &(mark * 2));
+        let stats = client.get_market_stats(&symbol);
+        assert_eq!((stats.long_oi, stats.short_oi, stats.net_oi), (0, 0, 0));
+
+        let hash = BytesN::from_array(&env, &[0u8; 32]);
+        assert_eq!(client.try_upgrade(&alice, &hash), Err(Ok(Error::Unauthorized)));
+    }
 
-        // Open long position
-        let _ = client.open_position(&trader, &symbol, &10_000_000, &2_000_000_000, &mark); // limit = current price
+    // Built from a contract whose only function, `get_schema_version`, returns 0
+    const UPGRADED_WASM: &[u8] = include_bytes!("../test_wasms/upgraded.wasm");
 
-        let position = client.get_position(&trader, &symbol).unwrap();
-        assert_eq!(position.size, 10_000_000);
-        assert_eq!(position.margin, 2_000_000_000);
+    #[test]
+    fn test_upgrade_and_migrate() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        let mark2 = client.get_mark_price_view(&symbol);
-        // Close half with limit
-        let _ = client.close_position(&trader, &symbol, &5_000_000, &mark2);
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &limit);
+        let position = client.get_position(&alice, &symbol).unwrap();
+        store_legacy_state(&env, &contract_id, &[&alice]);
+
+        // The owner swaps in the uploaded code, which serves the next call
+        let wasm_hash = env.deployer().upload_contract_wasm(UPGRADED_WASM);
+        client.upgrade(&admin, &wasm_hash);
+        let event = UpgradeEvent { version: EVENT_VERSION, caller: admin.clone(), wasm_hash: wasm_hash.clone() };
+        assert_eq!(
+            env.events().all(),
+            vec![&env, (contract_id.clone(), (symbol_short!("UPGRADE"),).into_val(&env), event.into_val(&env))]
+        );
+        assert_eq!(client.get_schema_version(), 0);
+
+        // The test host cannot run this crate as wasm, so the native build
+        // stands in for the upgraded code on the same storage
+        env.register_at(&contract_id, FlashPerp, ());
+        assert_eq!(client.get_schema_version(), 1);
+        assert_eq!(client.try_deposit_collateral(&alice, &1), Err(Ok(Error::MigrationPending)));
+
+        assert_eq!(client.migrate(&admin, &vec![&env, alice.clone()]), 6);
+        client.complete_migration(&admin);
+        assert_eq!(client.get_schema_version(), SCHEMA_VERSION);
+
+        let migrated = client.get_position(&alice, &symbol).unwrap();
+        assert_eq!((migrated.size, migrated.margin), (position.size, position.margin));
+        let _ = client.close_position(&alice, &symbol, &100_000_000, &0);
+        assert!(client.get_position(&alice, &symbol).is_none());
+    }
 
-        let position = client.get_position(&trader, &symbol).unwrap();
-        assert_eq!(position.size, 5_000_000);
+    #[test]
+    fn test_storage_ttl() {
+        let env = Env::default();
+        let (contract_id, client, _, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
-        // --- Slippage failure case ---
-        let bad_limit = mark - 1; // for long position, too low
-        let res = client.try_open_position(&trader, &symbol, &1_000_000, &200_000_000, &bad_limit);
-        assert_eq!(res, Err(Ok(Error::SlippageExceeded)));
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &limit);
+
+        let position_key = DataKey::Position(alice.clone(), symbol.clone());
+        let market_key = DataKey::Market(symbol.clone());
+        let ttl = |key: &DataKey| env.as_contract(&contract_id, || env.storage().persistent().get_ttl(key));
+        let instance_ttl = || env.as_contract(&contract_id, || env.storage().instance().get_ttl());
+
+        assert_eq!(ttl(&position_key), ACCOUNT_BUMP_AMOUNT);
+        assert_eq!(ttl(&market_key), MARKET_BUMP_AMOUNT);
+        assert_eq!(ttl(&DataKey::Vault), PROTOCOL_BUMP_AMOUNT);
+        assert_eq!(instance_ttl(), INSTANCE_BUMP_AMOUNT);
+
+        // An idle account is kept alive by a keeper, positions included
+        env.ledger().with_mut(|l| l.sequence_number += 25 * DAY_IN_LEDGERS);
+        assert_eq!(ttl(&position_key), 5 * DAY_IN_LEDGERS);
+        client.bump_account(&alice);
+        assert_eq!(ttl(&position_key), ACCOUNT_BUMP_AMOUNT);
+        assert_eq!(ttl(&DataKey::Account(alice.clone())), ACCOUNT_BUMP_AMOUNT);
+        assert_eq!(instance_ttl(), INSTANCE_BUMP_AMOUNT);
+
+        // Market entries are only extended once below their threshold
+        client.bump_market(&symbol);
+        assert_eq!(ttl(&market_key), 35 * DAY_IN_LEDGERS);
+        env.ledger().with_mut(|l| l.sequence_number += 25 * DAY_IN_LEDGERS);
+        client.bump_market(&symbol);
+        assert_eq!(ttl(&market_key), MARKET_BUMP_AMOUNT);
+        assert_eq!(client.try_bump_market(&symbol_short!("DOGE")), Err(Ok(Error::InvalidSymbol)));
+
+        // Any call keeps the instance alive
+        env.ledger().with_mut(|l| l.sequence_number += 25 * DAY_IN_LEDGERS);
+        let _ = client.get_fee_bp();
+        assert_eq!(instance_ttl(), INSTANCE_BUMP_AMOUNT);
     }
 
     #[test]
-    fn test_overflow_guard() {
-        use core::i128::MAX as I128MAX;
+    fn test_market_state_footprint() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+        let limit = client.get_mark_price_view(&symbol) * 2;
+
+        // Ledger reads of mark price, funding and open, each measured against
+        // the per-field layout and then the consolidated one
+        let measure = |trader: &Address, legacy: bool| {
+            let split = || {
+                if legacy {
+                    store_legacy_market(&env, &contract_id, &symbol);
+                }
+            };
+            split();
+            let _ = client.get_mark_price_view(&symbol);
+            let mark = env.cost_estimate().resources();
+            env.ledger().with_mut(|l| l.timestamp += FUNDING_PERIOD);
+            split();
+            let _ = client.poke_funding(&symbol);
+            let funding = env.cost_estimate().resources();
+            split();
+            let _ = client.open_position(trader, &symbol, &10_000_000, &2_100_000, &limit);
+            let open = env.cost_estimate().resources();
+            (mark, funding, open)
+        };
+
+        let (legacy_mark, legacy_funding, legacy_open) = measure(&alice, true);
+        store_legacy_market(&env, &contract_id, &symbol);
+        assert_eq!(client.migrate(&admin, &Vec::new(&env)), 1);
+        let (mark, funding, open) = measure(&bob, false);
+
+        assert!(mark.read_entries < legacy_mark.read_entries);
+        assert!(funding.read_entries < legacy_funding.read_entries);
+        assert!(open.read_entries < legacy_open.read_entries);
+        assert!(open.instructions < legacy_open.instructions);
+    }
+
+    #[test]
+    fn test_account_record() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+        let btc = symbol_short!("BTC");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let _ = client.withdraw_collateral(&alice, &1);
+        let flat_reads = env.cost_estimate().resources().read_entries;
+
+        // Ten units of notional in each market
+        let open = |symbol: &Symbol| {
+            let mark = client.get_mark_price_view(symbol);
+            let size = 10_000_000 * DEC_P / mark;
+            client.open_position(&alice, symbol, &size, &2_100_000, &(mark * 2));
+            size
+        };
+        let xlm_size = open(&xlm);
+        let _ = open(&btc);
+
+        let account = client.get_account(&alice);
+        assert_eq!(account.positions.len(), 2);
+        assert_eq!(account.margin_used, 4_200_000);
+        assert_eq!(account.free_collateral, account.collateral - 4_200_000);
+
+        // Isolated free collateral comes from the account record alone; the
+        // only extra reads are the rent bumps of the two open markets' entries
+        let _ = client.withdraw_collateral(&alice, &1);
+        assert_eq!(env.cost_estimate().resources().read_entries, flat_reads + 2 * 2);
+
+        let _ = client.close_position(&alice, &xlm, &xlm_size, &0);
+        let account = client.get_account(&alice);
+        assert_eq!(account.positions.len(), 1);
+        assert_eq!(account.positions.get(0).unwrap().symbol, btc);
+        assert_eq!(account.margin_used, 2_100_000);
+        assert_eq!(
+            client.try_set_margin_mode(&alice, &MarginMode::Cross),
+            Err(Ok(Error::PositionsOpen))
+        );
+    }
 
+    #[test]
+    fn test_typed_events() {
         let env = Env::default();
         env.mock_all_auths();
 
@@ -868,18 +5900,244 @@ mod test {
 
         let admin = Address::generate(&env);
         let token = Address::generate(&env);
-        let trader = Address::generate(&env);
+        let alice = Address::generate(&env);
         let symbol = symbol_short!("XLM");
 
+        // (topics, data) of the last event the contract published
+        let last_event = || {
+            let (emitter, topics, data) = env.events().all().last().unwrap();
+            assert_eq!(emitter, contract_id);
+            (topics, data)
+        };
+
         let _ = client.initialize(&admin, &token);
+        let _ = client.lp_deposit(&admin, &1_000_000_000);
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("DEPOSIT"), alice.clone()).into_val(&env));
+        let deposit = CollateralEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((deposit.version, deposit.amount, deposit.collateral), (EVENT_VERSION, 100_000_000, 100_000_000));
+
+        let limit = client.get_mark_price_view(&symbol) * 2;
+        let _ = client.open_position(&alice, &symbol, &100_000_000, &2_100_000, &limit);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("OPEN"), alice.clone(), symbol.clone()).into_val(&env));
+        let open = OpenEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!(open.position, client.get_position(&alice, &symbol).unwrap());
+        assert_eq!(client.get_account(&alice).collateral, 100_000_000 - open.fee - open.funding_paid);
+        assert_eq!(open.fill_price * open.size / DEC_P, open.position.notional);
+
+        env.ledger().with_mut(|l| l.timestamp += FUNDING_PERIOD);
+        let _ = client.poke_funding(&symbol);
+        let funding = FundingEvent::try_from_val(&env, &last_event().1).unwrap();
+        assert_eq!(funding.index, client.get_market_stats(&symbol).funding_index);
+        assert_eq!(funding.timestamp, env.ledger().timestamp());
+
+        let _ = client.close_position(&alice, &symbol, &100_000_000, &0);
+        let close = CloseEvent::try_from_val(&env, &last_event().1).unwrap();
+        assert_eq!(close.position.size, 0);
+        assert_eq!(close.funding_paid, open.position.size * funding.index / DEC_F);
+        assert!(close.fee > 0);
+
+        let _ = client.pause(&admin);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("STATUS"),).into_val(&env));
+        let status = StatusEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((status.caller, status.symbol, status.status), (admin.clone(), None, MarketStatus::Halted));
+
+        let _ = client.unpause(&admin);
+        apply_change(&env, &client, &admin, ParamChange::FeeBp(10));
+        let config = ConfigEvent::try_from_val(&env, &last_event().1).unwrap();
+        assert_eq!((config.proposer, config.change), (admin.clone(), ParamChange::FeeBp(10)));
+
+        let _ = client.set_margin_mode(&alice, &MarginMode::Cross);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("MRGN_MODE"), alice.clone()).into_val(&env));
+        let mode = MarginModeEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((mode.version, mode.mode), (EVENT_VERSION, MarginMode::Cross));
+
+        let _ = client.grant_role(&admin, &Role::Guardian, &alice);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("GRANT"), Role::Guardian).into_val(&env));
+        let grant = RoleEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((grant.owner, grant.account), (admin.clone(), alice.clone()));
+
+        let id = client.queue_change(&admin, &ParamChange::FeeBp(20));
+        let _ = client.cancel_change(&alice, &id);
+        let (topics, data) = last_event();
+        assert_eq!(topics, (symbol_short!("CANCELLED"), id).into_val(&env));
+        let cancelled = QueueEvent::try_from_val(&env, &data).unwrap();
+        assert_eq!((cancelled.caller, cancelled.change), (alice, ParamChange::FeeBp(20)));
+
+        let _ = client.set_backstop(&admin, &None);
+        let backstop = BackstopEvent::try_from_val(&env, &last_event().1).unwrap();
+        assert_eq!((backstop.version, backstop.backstop), (EVENT_VERSION, None));
+    }
+
+    #[test]
+    fn test_trade_ledger() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let alice = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+
+        let _ = client.deposit_collateral(&alice, &100_000_000);
+        assert_eq!(client.get_trade_ledger(&alice, &xlm), TradeLedger::default());
+
+        let mark = client.get_mark_price_view(&xlm);
+        let size = 10_000_000 * DEC_P / mark;
+        let _ = client.open_position(&alice, &xlm, &size, &2_100_000, &(mark * 2));
+        env.ledger().with_mut(|l| l.timestamp += 3_600);
+        let _ = client.close_position(&alice, &xlm, &size, &0);
+
+        let ledger = client.get_trade_ledger(&alice, &xlm);
+        assert!(ledger.fees_paid > 0);
+        assert_eq!(ledger.liquidation_losses, 0);
+        // Both legs count toward volume, each worth roughly ten units
+        assert!(ledger.volume > 19_000_000 && ledger.volume < 21_000_000);
+
+        // Every realized flow is accounted for in the collateral delta
+        let collateral = client.get_account(&alice).collateral;
+        assert_eq!(
+            collateral - 100_000_000,
+            ledger.realized_pnl - ledger.fees_paid - ledger.funding_paid + ledger.funding_received
+        );
+
+        let ledgers = client.get_trade_ledgers(&alice);
+        assert_eq!(ledgers.len(), 1);
+        assert_eq!(ledgers.get(xlm.clone()).unwrap(), ledger);
+
+        // Isolated losses past the margin are never charged, so they are not
+        // realized either, whether the position is liquidated or settled
+        let bob = Address::generate(&env);
+        let carol = Address::generate(&env);
+        for trader in [&bob, &carol] {
+            let _ = client.deposit_collateral(trader, &10_000_000);
+            let _ = client.open_position(trader, &xlm, &size, &2_100_000, &(mark * 2));
+        }
+        set_mock_price(&env, &contract_id, &xlm, 50_000);
+        let _ = client.liquidate(&admin, &carol, &xlm);
+        let _ = client.settle_market(&admin, &xlm, &50_000);
+        let _ = client.claim_settlement(&bob, &xlm);
+
+        for trader in [&bob, &carol] {
+            let ledger = client.get_trade_ledger(trader, &xlm);
+            assert!(ledger.realized_pnl >= -2_100_000);
+            assert_eq!(
+                client.get_account(trader).collateral - 10_000_000,
+                ledger.realized_pnl - ledger.fees_paid - ledger.funding_paid + ledger.funding_received
+                    - ledger.liquidation_losses
+            );
+        }
+    }
+
+    #[test]
+    fn test_token_collateral() {
+        let env = Env::default();
+        let (contract_id, client, admin, _) = setup(&env);
+        let xlm_token = Address::generate(&env);
+        let alice = Address::generate(&env);
+        let bob = Address::generate(&env);
+        let xlm = symbol_short!("XLM");
+        let btc = symbol_short!("BTC");
+
+        let _ = client.deposit_collateral(&bob, &100_000_000);
+        assert_eq!(
+            client.try_deposit_token_collateral(&alice, &xlm_token, &1),
+            Err(Ok(Error::AssetNotListed))
+        );
+
+        // XLM has 7 decimals and counts at 80% of its $0.10 oracle price
+        let asset = CollateralAsset { asset: xlm.clone(), decimals: 7, haircut_bp: 2_000, deposit_cap: 10_000_000_000 };
+        let bad = CollateralAsset { haircut_bp: 10_001, ..asset.clone() };
+        assert_eq!(
+            client.try_queue_change(&admin, &ParamChange::CollateralAsset(xlm_token.clone(), bad)),
+            Err(Ok(Error::InvalidAmount))
+        );
+        apply_change(&env, &client, &admin, ParamChange::CollateralAsset(xlm_token.clone(), asset.clone()));
+        assert_eq!(client.get_collateral_assets().get(xlm_token.clone()), Some(asset));
+
+        assert_eq!(
+            client.try_deposit_token_collateral(&alice, &xlm_token, &10_000_000_001),
+            Err(Ok(Error::DepositCapExceeded))
+        );
+        let _ = client.deposit_token_collateral(&alice, &xlm_token, &10_000_000_000);
+        let account = client.get_account(&alice);
+        assert_eq!(account.tokens.get(xlm_token.clone()), Some(10_000_000_000));
+        assert_eq!(account.token_value, 80_000_000);
+        assert_eq!(account.free_collateral, 80_000_000);
+        assert_eq!(account.equity, 80_000_000);
+
+        // Tokens back margin but cannot be withdrawn as USD
+        assert_eq!(client.try_withdraw_collateral(&alice, &1), Err(Ok(Error::InsufficientCollateral)));
+        let mark = client.get_mark_price_view(&btc);
+        let size = 100_000_000 * DEC_P / mark;
+        let _ = client.open_position(&alice, &btc, &size, &21_000_000, &(mark * 2));
+        let fee = -client.get_account(&alice).collateral;
+        assert_eq!(
+            client.try_withdraw_token_collateral(&alice, &xlm_token, &10_000_000_000),
+            Err(Ok(Error::InsufficientCollateral))
+        );
+
+        // The position's loss exceeds its margin; the USD shortfall stays as debt
+        set_mock_price(&env, &contract_id, &btc, 60_000_000_000);
+        let _ = client.liquidate(&bob, &alice, &btc);
+        let debt = 21_000_000 + fee;
+        assert_eq!(client.get_account(&alice).collateral, -debt);
+        assert_eq!(
+            client.try_liquidate_collateral(&bob, &alice, &xlm_token, &debt),
+            Err(Ok(Error::BelowMaintenanceMargin))
+        );
+
+        // Once the tokens no longer cover the debt, a liquidator buys them at
+        // the oracle price less the bonus; what they cannot cover is written off
+        set_mock_price(&env, &contract_id, &xlm, 20_000);
+        let bob_collateral = client.get_account(&bob).collateral;
+        let vault_balance = client.get_vault_view().balance;
+        let seized = client.liquidate_collateral(&bob, &alice, &xlm_token, &100_000_000);
+        assert_eq!(seized, 10_000_000_000);
+        let repaid = 20_000_000 * 10_000 / (10_000 + BONUS_BP);
+        assert_eq!(client.get_account(&bob).collateral, bob_collateral - repaid);
+        assert_eq!(client.get_vault_view().balance, vault_balance - (debt - repaid));
+
+        let account = client.get_account(&alice);
+        assert_eq!(account.collateral, 0);
+        assert!(account.tokens.is_empty());
+        assert_eq!(client.get_token_collateral(&bob).get(xlm_token.clone()), Some(seized));
+        let _ = client.withdraw_token_collateral(&bob, &xlm_token, &seized);
+        assert!(client.get_token_collateral(&bob).is_empty());
+
+        // A settlement claim leaves the same debt for the tokens to cover
+        let carol = Address::generate(&env);
+        let eth = symbol_short!("ETH");
+        set_mock_price(&env, &contract_id, &xlm, 100_000);
+        let _ = client.deposit_token_collateral(&carol, &xlm_token, &10_000_000_000);
+        let _ = client.set_margin_mode(&carol, &MarginMode::Cross);
+        let mark = client.get_mark_price_view(&eth);
+        let size = 50_000_000 * DEC_P / mark;
+        let _ = client.open_position(&carol, &eth, &size, &10_000_000, &(mark * 2));
+        let fee = -client.get_account(&carol).collateral;
+        set_mock_price(&env, &contract_id, &eth, 3_000_000_000);
+        let _ = client.settle_market(&admin, &eth, &3_000_000_000);
+        let credited = client.claim_settlement(&carol, &eth);
+        assert!(credited < -10_000_000);
+        assert_eq!(client.get_account(&carol).collateral, credited - fee);
+    }
+
+    #[test]
+    fn test_overflow_guard() {
+        let env = Env::default();
+        let (_, client, _, _) = setup(&env);
+        let trader = Address::generate(&env);
+        let symbol = symbol_short!("XLM");
 
         // Deposit an enormous collateral so margin check is not the limiting factor
-        let _ = client.deposit_collateral(&trader, & (I128MAX / 2));
+        let _ = client.deposit_collateral(&trader, & (i128::MAX / 2));
 
         let mark = client.get_mark_price_view(&symbol);
 
-        let size = I128MAX / 2;
-        let margin = I128MAX / 2;
+        let size = i128::MAX / 2;
+        let margin = i128::MAX / 2;
 
         let res = client.try_open_position(&trader, &symbol, &size, &margin, &mark);
 
@@ -21,8 +21,8 @@ const CONTRACT_ERRORS: Record<number, ContractError> = {
   },
   4: {
     code: 4,
-    message: 'InvalidPrice',
-    userMessage: 'Invalid price. Please check your input.',
+    message: 'PositionTooLarge',
+    userMessage: 'Position size too large. Please reduce trade size.',
   },
   5: {
     code: 5,
@@ -41,38 +41,38 @@ const CONTRACT_ERRORS: Record<number, ContractError> = {
   },
   8: {
     code: 8,
-    message: 'MarketNotFound',
-    userMessage: 'Market not supported.',
+    message: 'Unauthorized',
+    userMessage: 'You are not authorized to perform this action.',
   },
   9: {
     code: 9,
-    message: 'InsufficientLiquidity',
-    userMessage: 'Not enough liquidity for this trade size.',
+    message: 'Paused',
+    userMessage: 'Trading is temporarily paused.',
   },
   10: {
     code: 10,
-    message: 'PriceOutdated',
-    userMessage: 'Price data is outdated. Please try again.',
+    message: 'OracleUnavailable',
+    userMessage: 'Price feed is unavailable. Please try again later.',
   },
   11: {
     code: 11,
-    message: 'MaxLeverageExceeded',
-    userMessage: 'Leverage too high. Please reduce position size.',
+    message: 'OracleStale',
+    userMessage: 'Price data is outdated. Please try again.',
   },
   12: {
     code: 12,
-    message: 'MinPositionSize',
-    userMessage: 'Position size too small. Minimum trade size required.',
+    message: 'InvalidSymbol',
+    userMessage: 'Market not supported.',
   },
   13: {
     code: 13,
-    message: 'MaxPositionSize',
-    userMessage: 'Position size too large. Please reduce trade size.',
+    message: 'ZeroAmount',
+    userMessage: 'Amount must be greater than zero.',
   },
   14: {
     code: 14,
-    message: 'SelfTrade',
-    userMessage: 'Cannot trade against your own order.',
+    message: 'SelfLiquidation',
+    userMessage: 'You cannot liquidate your own position.',
   },
   15: {
     code: 15,
@@ -86,13 +86,98 @@ const CONTRACT_ERRORS: Record<number, ContractError> = {
   },
   17: {
     code: 17,
-    message: 'Unauthorized',
-    userMessage: 'You are not authorized to perform this action.',
+    message: 'PositionsOpen',
+    userMessage: 'Close all positions before changing margin mode.',
   },
   18: {
     code: 18,
-    message: 'Paused',
-    userMessage: 'Trading is temporarily paused.',
+    message: 'NotCrossMargin',
+    userMessage: 'This account is not cross-margined.',
+  },
+  19: {
+    code: 19,
+    message: 'UtilizationExceeded',
+    userMessage: 'The vault cannot take on more exposure right now.',
+  },
+  20: {
+    code: 20,
+    message: 'CooldownActive',
+    userMessage: 'Withdrawal cooldown has not elapsed yet.',
+  },
+  21: {
+    code: 21,
+    message: 'OpenInterestCap',
+    userMessage: 'Open interest limit reached for this market.',
+  },
+  22: {
+    code: 22,
+    message: 'ReduceOnly',
+    userMessage: 'This market only accepts position reductions.',
+  },
+  23: {
+    code: 23,
+    message: 'MarketSettled',
+    userMessage: 'This market has been settled. Claim your position instead.',
+  },
+  24: {
+    code: 24,
+    message: 'MarketNotSettled',
+    userMessage: 'This market has not been settled.',
+  },
+  25: {
+    code: 25,
+    message: 'PriceOutOfBounds',
+    userMessage: 'Price is too far from the oracle price.',
+  },
+  26: {
+    code: 26,
+    message: 'ShutDown',
+    userMessage: 'The protocol has shut down. Claim your funds instead.',
+  },
+  27: {
+    code: 27,
+    message: 'NotShutDown',
+    userMessage: 'The protocol has not shut down.',
+  },
+  28: {
+    code: 28,
+    message: 'TallyPending',
+    userMessage: 'Shutdown claims open once every account has been settled.',
+  },
+  29: {
+    code: 29,
+    message: 'InvalidRole',
+    userMessage: 'This role cannot be granted or revoked.',
+  },
+  30: {
+    code: 30,
+    message: 'NoPendingAdmin',
+    userMessage: 'There is no pending admin transfer.',
+  },
+  31: {
+    code: 31,
+    message: 'TimelockActive',
+    userMessage: 'This change is still timelocked.',
+  },
+  32: {
+    code: 32,
+    message: 'ChangeNotFound',
+    userMessage: 'No queued change with this id.',
+  },
+  33: {
+    code: 33,
+    message: 'MigrationPending',
+    userMessage: 'The contract is being upgraded. Please try again later.',
+  },
+  34: {
+    code: 34,
+    message: 'AssetNotListed',
+    userMessage: 'This token is not accepted as collateral.',
+  },
+  35: {
+    code: 35,
+    message: 'DepositCapExceeded',
+    userMessage: 'Deposit cap reached for this collateral token.',
   },
 };
 
@@ -5,6 +5,7 @@ export interface Position {
   notional: bigint;
   margin: bigint;
   funding_index: bigint;
+  last_update: bigint;
 }
 
 export interface Reserve {
@@ -38,6 +39,36 @@ export enum ContractError {
   InvalidSymbol = 12,
   ZeroAmount = 13,
   SelfLiquidation = 14,
+  SlippageExceeded = 15,
+  Overflow = 16,
+  PositionsOpen = 17,
+  NotCrossMargin = 18,
+  UtilizationExceeded = 19,
+  CooldownActive = 20,
+  OpenInterestCap = 21,
+  ReduceOnly = 22,
+  MarketSettled = 23,
+  MarketNotSettled = 24,
+  PriceOutOfBounds = 25,
+  ShutDown = 26,
+  NotShutDown = 27,
+  TallyPending = 28,
+  InvalidRole = 29,
+  NoPendingAdmin = 30,
+  TimelockActive = 31,
+  ChangeNotFound = 32,
+  MigrationPending = 33,
+  AssetNotListed = 34,
+  DepositCapExceeded = 35,
+}
+
+// Access control roles
+export enum Role {
+  Owner = 0,
+  Guardian = 1,
+  RiskManager = 2,
+  OracleManager = 3,
+  Treasurer = 4,
 }
 
 // Transaction types
@@ -105,7 +136,18 @@ export interface FlashPerpClientInterface {
   getFundingData(symbol: string): Promise<FundingData>;
   
   // Admin functions
-  pause(): Promise<TransactionResult>;
-  unpause(): Promise<TransactionResult>;
+  pause(caller: string): Promise<TransactionResult>;
+  unpause(caller: string): Promise<TransactionResult>;
   updateFunding(symbol: string, oraclePrice: bigint): Promise<TransactionResult>;
+
+  // Roles
+  grantRole(owner: string, role: Role, account: string): Promise<TransactionResult>;
+  revokeRole(owner: string, role: Role, account: string): Promise<TransactionResult>;
+  hasRole(role: Role, account: string): Promise<boolean>;
+  getRoleMembers(role: Role): Promise<string[]>;
+
+  // Treasury
+  withdrawTreasury(caller: string, token: string, to: string, amount: bigint): Promise<TransactionResult>;
+  getTreasury(): Promise<Map<string, bigint>>;
+  getProtocolFeeBp(): Promise<bigint>;
 }
\ No newline at end of file
@@ -38,11 +38,15 @@ echo "🔧 Initialising FlashPerp with token address …"
 stellar contract invoke --id flashperp --network "$NETWORK" --source "$IDENTITY" -- initialize \
   --admin "$IDENTITY" --token_addr usdc >/dev/null
 
-echo "💰 Minting 100k USDC to deployer + approving 10k to FlashPerp …"
+echo "💰 Minting 100k USDC to deployer + approving 20k to FlashPerp …"
 stellar contract invoke --id usdc --network "$NETWORK" --source "$IDENTITY" -- mint \
   --to "$IDENTITY" --amount 100000000000 >/dev/null
 stellar contract invoke --id usdc --network "$NETWORK" --source "$IDENTITY" -- approve \
-  --from "$IDENTITY" --spender flashperp --amount 10000000000 --expiration_ledger 999999 >/dev/null
+  --from "$IDENTITY" --spender flashperp --amount 20000000000 --expiration_ledger 999999 >/dev/null
+
+echo "🌊 Seeding 10k USDC of LP vault liquidity …"
+stellar contract invoke --id flashperp --network "$NETWORK" --source "$IDENTITY" -- lp_deposit \
+  --lp "$IDENTITY" --amount 10000000000 >/dev/null
 
 echo "🏦 Depositing 10k USDC collateral …"
 stellar contract invoke --id flashperp --network "$NETWORK" --source "$IDENTITY" -- deposit_collateral \